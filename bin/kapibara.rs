@@ -1,12 +1,19 @@
 //! Kapibara main program
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand, ValueEnum};
-use env_logger::Env;
 use tokio::{fs, signal};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt,
+    EnvFilter, Layer,
+};
 
-use kapibara::{Codec, Dispatch, DispatchOption};
+use kapibara::{
+    logs::{BroadcastLayer, LogFormat, LogOption},
+    route::rule_set::{self, RuleSetEntries},
+    Codec, Dispatch, DispatchOption, ReloadReport,
+};
 
 #[derive(Debug, Parser)]
 #[command(version)]
@@ -45,11 +52,48 @@ enum Generate {
         #[arg(short, long, value_delimiter = ',')]
         domain: Vec<String>,
     },
+    /// compile text rule set into binary format
+    RuleSet {
+        #[arg(short, long, value_name = "FILE")]
+        input: PathBuf,
+        #[arg(short, long, value_name = "FILE")]
+        output: PathBuf,
+    },
 }
 
-fn init_logger(level: LogLevel) {
-    env_logger::Builder::from_env(Env::default())
-        .filter_level(level.into())
+// filter is RUST_LOG, then filter of config, then level of command line.
+// events are also sent to clash api log stream
+fn init_logger(level: LogLevel, opt: Option<&LogOption>) {
+    let opt = opt.cloned().unwrap_or_default();
+
+    let filter = std::env::var(EnvFilter::DEFAULT_ENV)
+        .ok()
+        .or(opt.filter)
+        .and_then(|f| match EnvFilter::try_new(&f) {
+            Ok(filter) => Some(filter),
+            Err(e) => {
+                eprintln!("[main] invalid log filter {}: {}", f, e);
+                None
+            }
+        })
+        .unwrap_or_else(|| EnvFilter::default().add_directive(LevelFilter::from(level).into()));
+
+    let span_events = if opt.span_timing {
+        FmtSpan::CLOSE
+    } else {
+        FmtSpan::NONE
+    };
+    let fmt = tracing_subscriber::fmt::layer().with_span_events(span_events);
+    let fmt = match opt.format {
+        LogFormat::Pretty => fmt.pretty().boxed(),
+        LogFormat::Compact => fmt.compact().boxed(),
+        LogFormat::Json => fmt.json().boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(BroadcastLayer)
+        .with(filter)
         .init();
 }
 
@@ -57,46 +101,146 @@ fn init_logger(level: LogLevel) {
 async fn main() {
     let cli = Cli::parse();
 
-    init_logger(cli.log);
-
     match cli.command {
         Commands::Run { config } => {
-            if let Err(err) = run(config).await {
-                log::error!("[main::run] {}", err);
+            // log option is in config, parse it first
+            let opt = parse_config(&config).await;
+            init_logger(cli.log, opt.as_ref().ok().and_then(|o| o.log.as_ref()));
+
+            let result = match opt {
+                Ok(opt) => run(config, opt).await,
+                Err(e) => Err(e),
+            };
+            if let Err(err) = result {
+                tracing::error!("[main::run] {}", err);
             }
         }
         Commands::Test { config } => {
+            init_logger(cli.log, None);
             if let Err(err) = test(config).await {
-                log::error!("[main::test] {}", err);
+                tracing::error!("[main::test] {}", err);
             }
         }
-        Commands::Gen(gen) => match gen {
-            Generate::Uuid => {
-                let uuid = uuid::Uuid::new_v4();
-                println!("{}", uuid);
-            }
-            Generate::Cert { domain } => {
-                if let Err(err) = gen_cert(domain).await {
-                    log::error!("[main::gen::cert] {}", err);
+        Commands::Gen(gen) => {
+            init_logger(cli.log, None);
+            match gen {
+                Generate::Uuid => {
+                    let uuid = uuid::Uuid::new_v4();
+                    println!("{}", uuid);
+                }
+                Generate::Cert { domain } => {
+                    if let Err(err) = gen_cert(domain).await {
+                        tracing::error!("[main::gen::cert] {}", err);
+                    }
+                }
+                Generate::RuleSet { input, output } => {
+                    if let Err(err) = gen_rule_set(input, output).await {
+                        tracing::error!("[main::gen::rule_set] {}", err);
+                    }
                 }
             }
-        },
+        }
     }
 }
 
-async fn run(config: PathBuf) -> Result<()> {
-    let dispatch_option = parse_config(&config).await?;
+async fn run(config: PathBuf, dispatch_option: DispatchOption) -> Result<()> {
     let mut dispatcher = Dispatch::init(dispatch_option)?;
 
     dispatcher.start()?;
 
-    let _ = signal::ctrl_c().await;
+    // reload on SIGHUP, when config file changed or asked by api
+    let mut modified = config_modified(&config).await;
+    let mut check = tokio::time::interval(CONFIG_CHECK_INTERVAL);
+    let mut hangup = hangup()?;
+    loop {
+        let request = tokio::select! {
+            _ = signal::ctrl_c() => break,
+            _ = hangup.recv() => {
+                tracing::info!("[main::run] SIGHUP received, reload config");
+                None
+            }
+            _ = check.tick() => {
+                let m = config_modified(&config).await;
+                if m == modified {
+                    continue;
+                }
+                tracing::info!("[main::run] config file changed, reload config");
+                None
+            }
+            req = dispatcher.reload_requested() => {
+                tracing::info!("[main::run] reload requested by api");
+                Some(req)
+            }
+        };
+
+        modified = config_modified(&config).await;
+        let result = reload(&mut dispatcher, &config).await;
+        match result {
+            Ok(ref report) => tracing::info!(
+                "[main::run] reloaded, started {:?}, stopped {:?}, kept {:?}",
+                report.started,
+                report.stopped,
+                report.kept
+            ),
+            Err(ref e) => tracing::error!("[main::run] reload failed, keep running config: {}", e),
+        }
+
+        if let Some(req) = request {
+            let _ = req.send(result.map_err(|e| e.to_string()));
+        }
+    }
+
+    // second ctrl-c closes at once
+    tokio::select! {
+        report = dispatcher.shutdown() => {
+            tracing::info!(
+                "[main::run] shutdown, {} drained, {} killed",
+                report.drained,
+                report.killed
+            );
+        }
+        _ = signal::ctrl_c() => {
+            tracing::info!("[main::run] force shutdown");
+        }
+    }
 
     dispatcher.close();
 
     Ok(())
 }
 
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+async fn reload(dispatcher: &mut Dispatch, config: &PathBuf) -> Result<ReloadReport> {
+    let opt = parse_config(config).await?;
+    Ok(dispatcher.reload(opt).await?)
+}
+
+async fn config_modified(config: &PathBuf) -> Option<std::time::SystemTime> {
+    fs::metadata(config).await.and_then(|m| m.modified()).ok()
+}
+
+#[cfg(unix)]
+fn hangup() -> Result<signal::unix::Signal> {
+    Ok(signal::unix::signal(signal::unix::SignalKind::hangup())?)
+}
+
+// no SIGHUP, only file change triggers reload
+#[cfg(not(unix))]
+fn hangup() -> Result<NoSignal> {
+    Ok(NoSignal)
+}
+
+#[cfg(not(unix))]
+struct NoSignal;
+
+#[cfg(not(unix))]
+impl NoSignal {
+    async fn recv(&mut self) -> Option<()> {
+        std::future::pending().await
+    }
+}
+
 async fn test(config: PathBuf) -> Result<()> {
     let opt = parse_config(&config).await?;
 
@@ -116,6 +260,17 @@ async fn gen_cert(domain: Vec<String>) -> Result<()> {
     Ok(())
 }
 
+async fn gen_rule_set(input: PathBuf, output: PathBuf) -> Result<()> {
+    let text = fs::read_to_string(input).await?;
+
+    let entries = RuleSetEntries::parse_text(&text)?;
+    let data = rule_set::encode(&entries)?;
+
+    fs::write(output, data).await?;
+
+    Ok(())
+}
+
 async fn parse_config(config: &PathBuf) -> Result<DispatchOption> {
     let opt_str = fs::read_to_string(config).await?;
 
@@ -123,7 +278,7 @@ async fn parse_config(config: &PathBuf) -> Result<DispatchOption> {
         match ext.to_ascii_lowercase().to_string_lossy().as_ref() {
             "yaml" => return Ok(Codec::Yaml.from_str(&opt_str)?),
             "json" => return Ok(Codec::Json.from_str(&opt_str)?),
-            other => log::warn!("invalid file extension {}", other),
+            other => tracing::warn!("invalid file extension {}", other),
         }
     }
 
@@ -146,15 +301,15 @@ enum LogLevel {
     Off,
 }
 
-impl From<LogLevel> for log::LevelFilter {
+impl From<LogLevel> for LevelFilter {
     fn from(value: LogLevel) -> Self {
         match value {
-            LogLevel::Trace => Self::Trace,
-            LogLevel::Debug => Self::Debug,
-            LogLevel::Info => Self::Info,
-            LogLevel::Warn => Self::Warn,
-            LogLevel::Error => Self::Error,
-            LogLevel::Off => Self::Off,
+            LogLevel::Trace => Self::TRACE,
+            LogLevel::Debug => Self::DEBUG,
+            LogLevel::Info => Self::INFO,
+            LogLevel::Warn => Self::WARN,
+            LogLevel::Error => Self::ERROR,
+            LogLevel::Off => Self::OFF,
         }
     }
 }
@@ -1,9 +1,18 @@
 //! Show all option
 
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use kapibara::{
-    Codec, DispatchOption, DnsOption, InboundOption, OutboundOption, RouteOption, RouteRuleOption,
+    access::AccessLogFormat,
+    group::{
+        load_balance::{self, HashKey},
+        GroupKindOption,
+    },
+    logs::{LogFormat, LogOption},
+    AccessLogOption, ApiOption, ClashApiOption, Codec, DispatchOption, DnsOption, DomainStrategy,
+    FailoverOption, GroupOption, InboundOption, LoadBalanceOption, MetricsOption, OutboundOption,
+    RaceOption, RejectOption, RouteOption, RouteRuleOption, SelectorOption, SniffOption,
+    UrlTestOption,
 };
 use kapibara_service::{
     socks::{option::SocksAuthOption, SocksInboundOption},
@@ -23,17 +32,35 @@ fn main() {
     let option = DispatchOption {
         route: RouteOption {
             rules: vec![
+                RouteRuleOption {
+                    dns: false,
+                    inbound: vec!["in-1".into()],
+                    outbound: "out-2".into(),
+                    domain_suffix: vec!["lan".into()],
+                    ..Default::default()
+                },
+                RouteRuleOption {
+                    outbound: "block".into(),
+                    port: vec![25],
+                    ..Default::default()
+                },
                 RouteRuleOption {
                     dns: false,
                     inbound: vec!["in-1".into()],
                     outbound: "out-1".into(),
+                    ..Default::default()
                 },
                 RouteRuleOption {
                     dns: true,
                     inbound: vec!["in-2".into()],
                     outbound: "out-2".into(),
+                    ..Default::default()
                 },
             ],
+            final_outbound: Some("out-1".into()),
+            geoip: None,
+            rule_set: vec![],
+            domain_strategy: DomainStrategy::AsIs,
         },
         dns: Some(DnsOption {
             resolve: ResolveOption {
@@ -61,6 +88,10 @@ fn main() {
                         pass: "test".into(),
                     }],
                 }),
+                sniff: Some(SniffOption {
+                    timeout: Duration::from_millis(300),
+                    override_dest: false,
+                }),
             },
             InboundOption {
                 tag: "in-2".into(),
@@ -84,12 +115,14 @@ fn main() {
                         uuid: "s17b1019d-a951-4bc5-a6e9-e8ece8aebcc3".to_string(),
                     }],
                 }),
+                sniff: None,
             },
         ],
         outbound: vec![
             OutboundOption {
                 tag: "out-1".into(),
                 timeout: Some(Duration::from_secs(30)),
+                detour: None,
                 service: OutboundServiceOption::Vless(VlessOutboundOption {
                     uuid: "s17b1019d-a951-4bc5-a6e9-e8ece8aebcc3".to_string(),
                     flow: None,
@@ -117,8 +150,83 @@ fn main() {
                 },
                 service: OutboundServiceOption::Direct,
                 timeout: Some(Duration::from_secs(30)),
+                detour: None,
+            },
+        ],
+        reject: vec![RejectOption {
+            tag: "block".into(),
+            delay: Some(Duration::from_secs(10)),
+        }],
+        group: vec![
+            GroupOption {
+                tag: "proxy".into(),
+                opt: GroupKindOption::Selector(SelectorOption {
+                    outbounds: vec!["auto".into(), "out-1".into(), "out-2".into()],
+                    default: None,
+                }),
+            },
+            GroupOption {
+                tag: "auto".into(),
+                opt: GroupKindOption::UrlTest(UrlTestOption {
+                    outbounds: vec!["out-1".into(), "out-2".into()],
+                    dest: "www.gstatic.com:80".into(),
+                    payload: Some(
+                        "HEAD /generate_204 HTTP/1.1\r\nHost: www.gstatic.com\r\n\r\n".into(),
+                    ),
+                    interval: Duration::from_secs(300),
+                    timeout: Duration::from_secs(5),
+                    tolerance: Duration::from_millis(50),
+                }),
+            },
+            GroupOption {
+                tag: "balance".into(),
+                opt: GroupKindOption::LoadBalance(LoadBalanceOption {
+                    outbounds: vec!["out-1".into(), "out-2".into()],
+                    strategy: load_balance::Strategy::WeightedRandom,
+                    weight: HashMap::from([("out-1".into(), 3), ("out-2".into(), 1)]),
+                    hash_key: HashKey::Dest,
+                }),
+            },
+            GroupOption {
+                tag: "backup".into(),
+                opt: GroupKindOption::Failover(FailoverOption {
+                    outbounds: vec!["out-1".into(), "out-2".into()],
+                    max_failures: 3,
+                    cooldown: Duration::from_secs(30),
+                }),
+            },
+            GroupOption {
+                tag: "fastest".into(),
+                opt: GroupKindOption::Race(RaceOption {
+                    outbounds: vec!["out-1".into(), "out-2".into()],
+                }),
             },
         ],
+        state: Some("state.json".into()),
+        log: Some(LogOption {
+            format: LogFormat::Compact,
+            filter: Some("info,kapibara::dispatch=debug".to_owned()),
+            span_timing: false,
+        }),
+        access_log: Some(AccessLogOption {
+            path: "access.log".into(),
+            format: AccessLogFormat::Json,
+            max_size: Some(100 * 1024 * 1024),
+            max_age: Some(Duration::from_secs(24 * 60 * 60)),
+            max_files: 5,
+        }),
+        metrics: Some(MetricsOption {
+            listen: "127.0.0.1:9090".parse().unwrap(),
+        }),
+        api: Some(ApiOption {
+            listen: "127.0.0.1:9091".parse().unwrap(),
+            token: "change-me".to_owned(),
+        }),
+        clash_api: Some(ClashApiOption {
+            listen: "127.0.0.1:9092".parse().unwrap(),
+            secret: "change-me".to_owned(),
+        }),
+        shutdown_timeout: Duration::from_secs(30),
     };
 
     let yaml = Codec::Yaml.to_string(&option).unwrap();
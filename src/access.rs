@@ -0,0 +1,470 @@
+//! Kapibara Access Log
+//!
+//! One record per routed connection at close, written to a rotated file,
+//! or to the log if no file is configured.
+
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+
+// records waiting for writer, more are dropped
+const QUEUE_SIZE: usize = 4096;
+
+// wait before rotating again after a failure
+const ROTATE_RETRY: Duration = Duration::from_secs(60);
+
+fn default_max_files() -> usize {
+    5
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Logfmt,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessLogOption {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    // rotate when file is larger, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<u64>,
+    // rotate when file is older
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<Duration>,
+    // rotated files kept as path.1 (newest) to path.N, default 5
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+/// Why a connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Termination {
+    // relay finished
+    Closed,
+    Rejected,
+    // killed by tracker
+    Killed,
+    // cancelled on shutdown
+    Shutdown,
+    Error,
+}
+
+impl Termination {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Closed => "closed",
+            Self::Rejected => "rejected",
+            Self::Killed => "killed",
+            Self::Shutdown => "shutdown",
+            Self::Error => "error",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessRecord {
+    // close time
+    pub time: SystemTime,
+    pub id: u64,
+    pub inbound: String,
+    pub outbound: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dialed: Option<String>,
+    pub rule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SocketAddr>,
+    pub network: String,
+    // as asked by client
+    pub dest: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sniffed: Option<String>,
+    // ip of domain destination, by route or dns rule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved: Option<SocketAddr>,
+    pub upload: u64,
+    pub download: u64,
+    pub duration: Duration,
+    pub termination: Termination,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AccessRecord {
+    pub fn to_json(&self) -> String {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        // readable time and duration instead of struct form
+        value["time"] = rfc3339(self.time).into();
+        value["duration"] = (self.duration.as_millis() as u64).into();
+        value.to_string()
+    }
+
+    pub fn to_logfmt(&self) -> String {
+        let mut fields = vec![
+            ("time", rfc3339(self.time)),
+            ("id", self.id.to_string()),
+            ("inbound", self.inbound.to_owned()),
+            ("outbound", self.outbound.to_owned()),
+        ];
+        let optional = [
+            ("dialed", self.dialed.clone()),
+            ("rule", Some(self.rule.to_owned())),
+            ("user", self.user.clone()),
+            ("source", self.source.map(|s| s.to_string())),
+            ("network", Some(self.network.to_owned())),
+            ("dest", Some(self.dest.to_owned())),
+            ("sniffed", self.sniffed.clone()),
+            ("resolved", self.resolved.map(|s| s.to_string())),
+        ];
+        fields.extend(optional.into_iter().filter_map(|(k, v)| v.map(|v| (k, v))));
+        fields.extend([
+            ("upload", self.upload.to_string()),
+            ("download", self.download.to_string()),
+            ("duration", (self.duration.as_millis() as u64).to_string()),
+            ("termination", self.termination.as_str().to_owned()),
+        ]);
+        if let Some(ref e) = self.error {
+            fields.push(("error", e.to_owned()));
+        }
+
+        let mut out = String::new();
+        for (i, (k, v)) in fields.iter().enumerate() {
+            if i > 0 {
+                out.push(' ');
+            }
+            let _ = write!(out, "{}={}", k, logfmt_value(v));
+        }
+        out
+    }
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+// quote value with space, quote or equal sign
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c == '"' || c == '=' || c.is_control())
+    {
+        return value.to_owned();
+    }
+
+    format!("{:?}", value)
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    // none if records go to the log, taken by close
+    tx: RwLock<Option<mpsc::Sender<String>>>,
+    dropped: AtomicU64,
+}
+
+impl Default for AccessLog {
+    // records go to the log
+    fn default() -> Self {
+        Self {
+            format: AccessLogFormat::Logfmt,
+            tx: RwLock::new(None),
+            dropped: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AccessLog {
+    /// Open file of option, the writer should be spawned to write records.
+    pub fn open(opt: AccessLogOption) -> std::io::Result<(Self, AccessWriter)> {
+        let format = opt.format;
+        let mut writer = AccessWriter::open(opt)?;
+
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        writer.rx = Some(rx);
+
+        let log = Self {
+            format,
+            tx: RwLock::new(Some(tx)),
+            dropped: AtomicU64::new(0),
+        };
+
+        Ok((log, writer))
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        let line = match self.format {
+            AccessLogFormat::Json => record.to_json(),
+            AccessLogFormat::Logfmt => record.to_logfmt(),
+        };
+
+        let tx = self.tx.read().unwrap();
+        let Some(ref tx) = *tx else {
+            tracing::info!("[access] {}", line);
+            return;
+        };
+
+        // never block connection on slow disk
+        if tx.try_send(line).is_err() {
+            let n = self.dropped.fetch_add(1, Ordering::Relaxed);
+            if n.is_multiple_of(1000) {
+                tracing::warn!("[access] writer is behind, {} records dropped", n + 1);
+            }
+        }
+    }
+
+    /// Drop the queue, so the writer finishes once queued records are
+    /// written. Records logged after go to the log.
+    pub fn close(&self) {
+        self.tx.write().unwrap().take();
+    }
+}
+
+pub struct AccessWriter {
+    opt: AccessLogOption,
+    rx: Option<mpsc::Receiver<String>>,
+    file: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+    // last failed rotate, records keep going to the current file
+    rotate_failed: Option<Instant>,
+}
+
+impl AccessWriter {
+    // sync, so error is returned by open of access log
+    fn open(opt: AccessLogOption) -> std::io::Result<Self> {
+        let file = open_options().open(&opt.path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            opt,
+            rx: None,
+            file: BufWriter::new(File::from_std(file)),
+            size,
+            opened: Instant::now(),
+            rotate_failed: None,
+        })
+    }
+
+    /// Write records until all senders dropped.
+    pub async fn run(mut self) {
+        let Some(mut rx) = self.rx.take() else {
+            return;
+        };
+
+        while let Some(line) = rx.recv().await {
+            if let Err(e) = self.write(&line).await {
+                tracing::error!("[access] {} {}", self.opt.path.display(), e);
+            }
+
+            // flush once queue is empty
+            if rx.is_empty() {
+                let _ = self.file.flush().await;
+            }
+        }
+
+        let _ = self.file.flush().await;
+    }
+
+    async fn write(&mut self, line: &str) -> std::io::Result<()> {
+        if self.should_rotate() {
+            if let Err(e) = self.rotate().await {
+                tracing::error!("[access] rotate {} {}", self.opt.path.display(), e);
+                self.rotate_failed = Some(Instant::now());
+            }
+        }
+
+        self.file.write_all(line.as_bytes()).await?;
+        self.file.write_all(b"\n").await?;
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+
+    fn should_rotate(&self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        if self
+            .rotate_failed
+            .is_some_and(|t| t.elapsed() < ROTATE_RETRY)
+        {
+            return false;
+        }
+
+        self.opt.max_size.is_some_and(|m| self.size >= m)
+            || self.opt.max_age.is_some_and(|a| self.opened.elapsed() >= a)
+    }
+
+    // current file is kept until the new one is open,
+    // on unix its handle follows the rename
+    async fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+
+        let path = &self.opt.path;
+        if self.opt.max_files == 0 {
+            fs::remove_file(path).await?;
+        } else {
+            // path.N-1 -> path.N, ..., path -> path.1
+            for i in (1..self.opt.max_files).rev() {
+                let from = rotated(path, i);
+                if fs::try_exists(&from).await.unwrap_or(false) {
+                    fs::rename(&from, rotated(path, i + 1)).await?;
+                }
+            }
+            fs::rename(path, rotated(path, 1)).await?;
+        }
+
+        let file = fs::OpenOptions::from(open_options()).open(path).await?;
+        self.size = file.metadata().await?.len();
+        self.file = BufWriter::new(file);
+        self.opened = Instant::now();
+        self.rotate_failed = None;
+
+        Ok(())
+    }
+}
+
+fn open_options() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    options
+}
+
+fn rotated(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            time: SystemTime::UNIX_EPOCH,
+            id: 1,
+            inbound: "in".into(),
+            outbound: "proxy".into(),
+            dialed: Some("out-1".into()),
+            rule: "rule#0".into(),
+            user: None,
+            source: Some("127.0.0.1:1234".parse().unwrap()),
+            network: "tcp".into(),
+            dest: "example.com:443".into(),
+            sniffed: None,
+            resolved: Some("1.1.1.1:443".parse().unwrap()),
+            upload: 10,
+            download: 20,
+            duration: Duration::from_millis(1500),
+            termination: Termination::Error,
+            error: Some("[outbound] connection refused".into()),
+        }
+    }
+
+    #[test]
+    fn test_format() {
+        let r = record();
+        assert_eq!(
+            r.to_logfmt(),
+            "time=1970-01-01T00:00:00.000Z id=1 inbound=in outbound=proxy dialed=out-1 \
+             rule=rule#0 source=127.0.0.1:1234 network=tcp dest=example.com:443 \
+             resolved=1.1.1.1:443 upload=10 download=20 duration=1500 termination=error \
+             error=\"[outbound] connection refused\""
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&r.to_json()).unwrap();
+        assert_eq!(json["time"], "1970-01-01T00:00:00.000Z");
+        assert_eq!(json["duration"], 1500);
+        assert_eq!(json["termination"], "error");
+        assert!(json.get("user").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let dir = std::env::temp_dir().join(format!("kapibara-access-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let (log, writer) = AccessLog::open(AccessLogOption {
+            path: path.clone(),
+            format: AccessLogFormat::Json,
+            max_size: Some(1),
+            max_age: None,
+            max_files: 2,
+        })
+        .unwrap();
+        let task = tokio::spawn(writer.run());
+
+        for _ in 0..4 {
+            log.log(&record());
+        }
+        drop(log);
+        task.await.unwrap();
+
+        // one record per file, oldest one removed
+        for p in [path.clone(), rotated(&path, 1), rotated(&path, 2)] {
+            let text = std::fs::read_to_string(p).unwrap();
+            assert_eq!(text.lines().count(), 1);
+        }
+        assert!(!rotated(&path, 3).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotate_failed() {
+        let dir = std::env::temp_dir().join(format!("kapibara-access-fail-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        // rename onto a non empty directory fails
+        std::fs::create_dir_all(rotated(&path, 1).join("busy")).unwrap();
+
+        let (log, writer) = AccessLog::open(AccessLogOption {
+            path: path.clone(),
+            format: AccessLogFormat::Json,
+            max_size: Some(1),
+            max_age: None,
+            max_files: 1,
+        })
+        .unwrap();
+        let task = tokio::spawn(writer.run());
+
+        for _ in 0..3 {
+            log.log(&record());
+        }
+        drop(log);
+        task.await.unwrap();
+
+        // no record lost, all in the current file
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,257 @@
+//! Kapibara Api
+//!
+//! Local json api to inspect and control a running dispatch.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use hyper::{body::Incoming, header, HeaderMap, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    dispatch::{ReloadReport, Runtime},
+    group::Group,
+    http::{self, Body},
+    stats::Stats,
+    Tracker,
+};
+
+// wait for the owner of dispatch to apply reload
+const RELOAD_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiOption {
+    pub listen: SocketAddr,
+    // bearer token, required by all endpoints except health and ready
+    pub token: String,
+}
+
+/// Reload asked by api, answered by the owner of `Dispatch`.
+pub type ReloadRequest = oneshot::Sender<Result<ReloadReport, String>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InboundInfo {
+    pub tag: String,
+    // service and transport name
+    pub service: String,
+    pub transport: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub listen: Option<SocketAddr>,
+    pub running: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboundInfo {
+    pub tag: String,
+    // service name, or kind of reject and group
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub members: Vec<String>,
+    // current member of selector and url_test
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub selected: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SelectBody {
+    selected: String,
+}
+
+/// Shared state of a dispatch, used by local control services.
+pub struct Control {
+    pub runtime: Arc<RwLock<Arc<Runtime>>>,
+    pub inbounds: Arc<RwLock<Vec<InboundInfo>>>,
+    // inbounds started and not shutting down
+    pub ready: Arc<AtomicBool>,
+    pub tracker: Arc<Tracker>,
+    pub stats: Arc<Stats>,
+    pub reload: mpsc::Sender<ReloadRequest>,
+}
+
+impl Control {
+    pub fn runtime(&self) -> Arc<Runtime> {
+        self.runtime.read().unwrap().clone()
+    }
+
+    pub fn inbounds(&self) -> Vec<InboundInfo> {
+        self.inbounds.read().unwrap().clone()
+    }
+
+    pub fn outbounds(&self) -> Vec<OutboundInfo> {
+        let runtime = self.runtime();
+        let manager = &runtime.outbound;
+
+        manager
+            .tags()
+            .into_iter()
+            .map(|tag| {
+                let group = manager.group(tag);
+                OutboundInfo {
+                    tag: tag.to_owned(),
+                    name: manager.name(tag).unwrap_or_default().to_owned(),
+                    members: group.map(|g| g.members().to_vec()).unwrap_or_default(),
+                    selected: group.and_then(Group::selected),
+                }
+            })
+            .collect()
+    }
+
+    /// Bytes from and to client of all connections, closed and active.
+    pub fn traffic(&self) -> (u64, u64) {
+        let closed = self.stats.snapshot();
+        let closed = closed.inbound.values();
+        let active = self.tracker.list();
+
+        let upload = closed.clone().map(|t| t.upload).sum::<u64>()
+            + active.iter().map(|c| c.upload).sum::<u64>();
+        let download = closed.map(|t| t.download).sum::<u64>()
+            + active.iter().map(|c| c.download).sum::<u64>();
+        (upload, download)
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    /// Ask for reload and wait for the result, none if nobody
+    /// handles reload requests in time.
+    pub async fn reload(&self) -> Option<Result<ReloadReport, String>> {
+        let (tx, rx) = oneshot::channel();
+        // full if the last request is still not handled
+        if self.reload.try_send(tx).is_err() {
+            return None;
+        }
+
+        match tokio::time::timeout(RELOAD_TIMEOUT, rx).await {
+            Ok(Ok(result)) => Some(result),
+            Ok(Err(_)) => Some(Err("reload request dropped".to_owned())),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Serve the api on listen address, never return.
+pub async fn serve(listener: TcpListener, token: String, control: Arc<Control>) {
+    let token = Arc::new(token);
+
+    http::serve(listener, move |req| {
+        let token = token.clone();
+        let control = control.clone();
+        async move { handle(&control, &token, req).await }
+    })
+    .await
+}
+
+async fn handle(control: &Control, token: &str, req: Request<Incoming>) -> Response<Body> {
+    let (parts, body) = req.into_parts();
+    let path = http::path(&parts.uri);
+    let path: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+
+    match (&parts.method, path.as_slice()) {
+        (&Method::GET, ["health"]) => {
+            return http::json(StatusCode::OK, &json!({ "status": "ok" }))
+        }
+        (&Method::GET, ["ready"]) => {
+            return if control.is_ready() {
+                http::json(StatusCode::OK, &json!({ "status": "ready" }))
+            } else {
+                http::error(StatusCode::SERVICE_UNAVAILABLE, "not ready")
+            }
+        }
+        _ => {}
+    }
+
+    if !authorized(&parts.headers, token) {
+        return http::error(StatusCode::UNAUTHORIZED, "unauthorized");
+    }
+
+    match (&parts.method, path.as_slice()) {
+        (&Method::GET, ["inbounds"]) => http::json(StatusCode::OK, &control.inbounds()),
+        (&Method::GET, ["outbounds"]) => http::json(StatusCode::OK, &control.outbounds()),
+        (&Method::PUT, ["outbounds", tag]) => {
+            let body: SelectBody = match http::read_json(body).await {
+                Ok(b) => b,
+                Err(res) => return res,
+            };
+            match control.runtime().outbound.select(tag, &body.selected) {
+                Ok(_) => http::json(StatusCode::OK, &json!({ "selected": body.selected })),
+                Err(e) => http::error(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::GET, ["routes"]) => http::json(StatusCode::OK, &control.runtime().route_option),
+        (&Method::GET, ["connections"]) => http::json(StatusCode::OK, &control.tracker.list()),
+        (&Method::DELETE, ["connections"]) => {
+            let killed = match http::query(&parts.uri, "user") {
+                Some(user) => control.tracker.kill_user(&user),
+                None => control.tracker.kill_all(),
+            };
+            http::json(StatusCode::OK, &json!({ "killed": killed }))
+        }
+        (&Method::GET, ["connections", id]) => {
+            match id.parse().ok().and_then(|id| control.tracker.get(id)) {
+                Some(info) => http::json(StatusCode::OK, &info),
+                None => http::not_found(),
+            }
+        }
+        (&Method::DELETE, ["connections", id]) => match id.parse() {
+            Ok(id) if control.tracker.kill(id) => {
+                http::json(StatusCode::OK, &json!({ "killed": 1 }))
+            }
+            _ => http::not_found(),
+        },
+        (&Method::GET, ["stats"]) => http::json(StatusCode::OK, &control.stats.snapshot()),
+        (&Method::DELETE, ["stats"]) => http::json(StatusCode::OK, &control.stats.reset()),
+        (&Method::POST, ["reload"]) => match control.reload().await {
+            Some(Ok(report)) => http::json(StatusCode::OK, &report),
+            Some(Err(e)) => http::error(StatusCode::INTERNAL_SERVER_ERROR, &e),
+            None => http::error(StatusCode::SERVICE_UNAVAILABLE, "reload is not handled"),
+        },
+        _ => http::not_found(),
+    }
+}
+
+pub(crate) fn authorized(headers: &HeaderMap, token: &str) -> bool {
+    let Some(value) = headers.get(header::AUTHORIZATION) else {
+        return false;
+    };
+
+    match value.to_str().ok().and_then(|v| v.strip_prefix("Bearer ")) {
+        Some(v) => constant_eq(v.as_bytes(), token.as_bytes()),
+        None => false,
+    }
+}
+
+// compare without early return
+pub(crate) fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_authorized() {
+        let mut headers = HeaderMap::new();
+        assert!(!authorized(&headers, "secret"));
+
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(authorized(&headers, "secret"));
+        assert!(!authorized(&headers, "secret2"));
+        assert!(!authorized(&headers, "secreT"));
+
+        headers.insert(header::AUTHORIZATION, "Basic secret".parse().unwrap());
+        assert!(!authorized(&headers, "secret"));
+    }
+}
@@ -0,0 +1,454 @@
+//! Kapibara Clash Api
+//!
+//! Subset of clash external controller, so existing dashboards
+//! (yacd, metacubexd) can drive a running dispatch.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::{stream, Stream, StreamExt};
+use hyper::{body::Incoming, header, HeaderMap, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{net::TcpListener, sync::broadcast::error::RecvError};
+use tracing::Level;
+
+use crate::{
+    api::{self, Control},
+    group::Group,
+    http::{self, Body},
+    logs, ConnInfo,
+};
+
+// interval of streamed traffic and connections
+const PUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClashApiOption {
+    pub listen: SocketAddr,
+    // empty means no auth, only allowed on loopback address,
+    // and requests from browsers are refused
+    #[serde(default)]
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Proxy {
+    name: String,
+    #[serde(rename = "type")]
+    typ: String,
+    udp: bool,
+    history: Vec<Delay>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    all: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    now: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Delay {
+    time: String,
+    delay: u128,
+}
+
+#[derive(Debug, Deserialize)]
+struct SelectBody {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Connections {
+    download_total: u64,
+    upload_total: u64,
+    connections: Vec<Connection>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Connection {
+    id: String,
+    metadata: Metadata,
+    upload: u64,
+    download: u64,
+    start: String,
+    // actual proxy first, rule target last
+    chains: Vec<String>,
+    rule: String,
+    rule_payload: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    network: String,
+    #[serde(rename = "type")]
+    typ: String,
+    #[serde(rename = "sourceIP")]
+    source_ip: String,
+    source_port: String,
+    #[serde(rename = "destinationIP")]
+    destination_ip: String,
+    destination_port: String,
+    host: String,
+    dns_mode: String,
+    process_path: String,
+    special_proxy: String,
+}
+
+impl From<ConnInfo> for Connection {
+    fn from(c: ConnInfo) -> Self {
+        let (host, port) = match c.dest.rsplit_once(':') {
+            Some((h, p)) => (h.trim_start_matches('[').trim_end_matches(']'), p),
+            None => (c.dest.as_str(), ""),
+        };
+        let (destination_ip, host) = match host.parse::<IpAddr>() {
+            Ok(ip) => (ip.to_string(), String::new()),
+            Err(_) => (String::new(), host.to_owned()),
+        };
+
+        let mut chains = vec![];
+        if let Some(ref dialed) = c.dialed {
+            if *dialed != c.outbound {
+                chains.push(dialed.to_owned());
+            }
+        }
+        chains.push(c.outbound.to_owned());
+
+        Self {
+            id: c.id.to_string(),
+            metadata: Metadata {
+                network: c.network.to_owned(),
+                typ: c.inbound.to_owned(),
+                source_ip: c.source.map(|s| s.ip().to_string()).unwrap_or_default(),
+                source_port: c.source.map(|s| s.port().to_string()).unwrap_or_default(),
+                destination_ip,
+                destination_port: port.to_owned(),
+                host,
+                dns_mode: "normal".to_owned(),
+                process_path: String::new(),
+                special_proxy: String::new(),
+            },
+            upload: c.upload,
+            download: c.download,
+            start: rfc3339(c.start),
+            chains,
+            rule: c.rule,
+            rule_payload: String::new(),
+        }
+    }
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+// clash type of outbound kind
+fn proxy_type(name: &str) -> String {
+    match name {
+        "selector" => "Selector".to_owned(),
+        "url_test" => "URLTest".to_owned(),
+        "load_balance" => "LoadBalance".to_owned(),
+        "failover" => "Fallback".to_owned(),
+        // closest clash type, dialing all members
+        "race" => "LoadBalance".to_owned(),
+        "reject" => "Reject".to_owned(),
+        other => {
+            let mut chars = other.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        }
+    }
+}
+
+fn proxies(control: &Control) -> HashMap<String, Proxy> {
+    let runtime = control.runtime();
+    let manager = &runtime.outbound;
+    let now = rfc3339(SystemTime::now());
+
+    // latency of url_test probes, shown as member history
+    let mut latency = HashMap::new();
+    for tag in manager.tags() {
+        if let Some(Group::UrlTest(g)) = manager.group(tag) {
+            for member in g.outbounds() {
+                if let Some(l) = g.latency(member) {
+                    latency.insert(member.to_owned(), l);
+                }
+            }
+        }
+    }
+
+    manager
+        .tags()
+        .into_iter()
+        .map(|tag| {
+            let group = manager.group(tag);
+            let now_member = match group {
+                Some(Group::Failover(g)) => g.candidates().first().map(|m| m.to_string()),
+                Some(g) => g.selected(),
+                None => None,
+            };
+            let proxy = Proxy {
+                name: tag.to_owned(),
+                typ: proxy_type(manager.name(tag).unwrap_or_default()),
+                udp: false,
+                history: latency
+                    .get(tag)
+                    .map(|l| Delay {
+                        time: now.to_owned(),
+                        delay: l.as_millis(),
+                    })
+                    .into_iter()
+                    .collect(),
+                all: group.map(|g| g.members().to_vec()),
+                now: now_member,
+            };
+            (tag.to_owned(), proxy)
+        })
+        .collect()
+}
+
+fn connections(control: &Control) -> Connections {
+    let (upload_total, download_total) = control.traffic();
+    Connections {
+        download_total,
+        upload_total,
+        connections: control
+            .tracker
+            .list()
+            .into_iter()
+            .map(Connection::from)
+            .collect(),
+    }
+}
+
+// call f every interval, forever
+fn interval<T, F>(control: Arc<Control>, f: F) -> impl Stream<Item = T>
+where
+    F: Fn(&Control) -> T,
+{
+    stream::unfold((control, f), |(control, f)| async move {
+        tokio::time::sleep(PUSH_INTERVAL).await;
+        let item = f(&control);
+        Some((item, (control, f)))
+    })
+}
+
+fn traffic(control: Arc<Control>) -> impl Stream<Item = Value> {
+    let last = control.traffic();
+    stream::unfold((control, last), |(control, last)| async move {
+        tokio::time::sleep(PUSH_INTERVAL).await;
+        let (up, down) = control.traffic();
+        let item = json!({
+            "up": up.saturating_sub(last.0),
+            "down": down.saturating_sub(last.1),
+        });
+        Some((item, (control, (up, down))))
+    })
+}
+
+fn log_level(name: &str) -> Option<Level> {
+    match name {
+        "debug" => Some(Level::DEBUG),
+        "warning" => Some(Level::WARN),
+        "error" => Some(Level::ERROR),
+        "silent" => None,
+        _ => Some(Level::INFO),
+    }
+}
+
+fn log_type(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "error",
+        Level::WARN => "warning",
+        Level::INFO => "info",
+        _ => "debug",
+    }
+}
+
+fn logs(level: Option<Level>) -> impl Stream<Item = Value> {
+    stream::unfold(logs::subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(entry) => return Some((entry, rx)),
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .filter_map(move |entry| {
+        let item = level
+            .filter(|l| entry.level <= *l)
+            .map(|_| json!({ "type": log_type(entry.level), "payload": entry.message }));
+        async move { item }
+    })
+}
+
+/// Serve the api on listen address, never return.
+pub async fn serve(listener: TcpListener, secret: String, control: Arc<Control>) {
+    let secret = Arc::new(secret);
+
+    http::serve(listener, move |req| {
+        let secret = secret.clone();
+        let control = control.clone();
+        async move {
+            let res = handle(control, &secret, req).await;
+            // web dashboards only with secret
+            if secret.is_empty() {
+                res
+            } else {
+                http::cors(res)
+            }
+        }
+    })
+    .await
+}
+
+async fn handle(control: Arc<Control>, secret: &str, mut req: Request<Incoming>) -> Response<Body> {
+    if from_browser(req.headers(), secret) {
+        return http::error(StatusCode::FORBIDDEN, "secret is required for browser");
+    }
+
+    // preflight of dashboards, no auth header
+    if req.method() == Method::OPTIONS {
+        return http::empty(StatusCode::NO_CONTENT);
+    }
+
+    if !authorized(&req, secret) {
+        return http::error(StatusCode::UNAUTHORIZED, "Unauthorized");
+    }
+
+    let path = http::path(req.uri());
+    let path: Vec<&str> = path.iter().map(|s| s.as_str()).collect();
+    let method = req.method().clone();
+
+    match (&method, path.as_slice()) {
+        (&Method::GET, []) => http::json(StatusCode::OK, &json!({ "hello": "clash" })),
+        (&Method::GET, ["version"]) => http::json(
+            StatusCode::OK,
+            &json!({ "version": format!("kapibara v{}", env!("CARGO_PKG_VERSION")) }),
+        ),
+        (&Method::GET, ["configs"]) => http::json(
+            StatusCode::OK,
+            &json!({ "port": 0, "socks-port": 0, "mode": "rule", "log-level": "info" }),
+        ),
+        (&Method::GET, ["proxies"]) => {
+            http::json(StatusCode::OK, &json!({ "proxies": proxies(&control) }))
+        }
+        (&Method::GET, ["proxies", name]) => match proxies(&control).remove(*name) {
+            Some(p) => http::json(StatusCode::OK, &p),
+            None => http::error(StatusCode::NOT_FOUND, "resource not found"),
+        },
+        (&Method::PUT, ["proxies", name]) => {
+            let body: SelectBody = match http::read_json(req.into_body()).await {
+                Ok(b) => b,
+                Err(res) => return res,
+            };
+            match control.runtime().outbound.select(name, &body.name) {
+                Ok(_) => http::empty(StatusCode::NO_CONTENT),
+                Err(e) => http::error(StatusCode::BAD_REQUEST, &e.to_string()),
+            }
+        }
+        (&Method::GET, ["connections"]) => {
+            if req.headers().contains_key(hyper::header::UPGRADE) {
+                http::stream(&mut req, interval(control, connections))
+            } else {
+                http::json(StatusCode::OK, &connections(&control))
+            }
+        }
+        (&Method::DELETE, ["connections"]) => {
+            control.tracker.kill_all();
+            http::empty(StatusCode::NO_CONTENT)
+        }
+        (&Method::DELETE, ["connections", id]) => {
+            if let Ok(id) = id.parse() {
+                control.tracker.kill(id);
+            }
+            http::empty(StatusCode::NO_CONTENT)
+        }
+        (&Method::GET, ["traffic"]) => http::stream(&mut req, traffic(control)),
+        (&Method::GET, ["logs"]) => {
+            let level = log_level(&http::query(req.uri(), "level").unwrap_or_default());
+            http::stream(&mut req, logs(level))
+        }
+        _ => http::error(StatusCode::NOT_FOUND, "resource not found"),
+    }
+}
+
+// without secret any web page could read and control the api,
+// websocket is not covered by cors, so refuse requests with origin
+fn from_browser(headers: &HeaderMap, secret: &str) -> bool {
+    secret.is_empty() && headers.contains_key(header::ORIGIN)
+}
+
+// bearer header, or token query for websocket
+fn authorized(req: &Request<Incoming>, secret: &str) -> bool {
+    if secret.is_empty() {
+        return true;
+    }
+
+    match http::query(req.uri(), "token") {
+        Some(token) => api::constant_eq(token.as_bytes(), secret.as_bytes()),
+        None => api::authorized(req.headers(), secret),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection() {
+        let info = ConnInfo {
+            id: 7,
+            inbound: "in".into(),
+            outbound: "proxy".into(),
+            dialed: Some("out-1".into()),
+            rule: "rule#0".into(),
+            user: None,
+            source: Some("127.0.0.1:1234".parse().unwrap()),
+            network: "tcp".into(),
+            dest: "[::1]:443".into(),
+            start: SystemTime::UNIX_EPOCH,
+            upload: 1,
+            download: 2,
+        };
+
+        let conn = Connection::from(info.clone());
+        assert_eq!(conn.id, "7");
+        assert_eq!(conn.chains, vec!["out-1", "proxy"]);
+        assert_eq!(conn.metadata.destination_ip, "::1");
+        assert_eq!(conn.metadata.destination_port, "443");
+        assert_eq!(conn.metadata.source_port, "1234");
+        assert_eq!(conn.start, "1970-01-01T00:00:00.000Z");
+
+        let conn = Connection::from(ConnInfo {
+            dialed: Some("proxy".into()),
+            dest: "example.com:80".into(),
+            ..info
+        });
+        assert_eq!(conn.chains, vec!["proxy"]);
+        assert_eq!(conn.metadata.host, "example.com");
+        assert!(conn.metadata.destination_ip.is_empty());
+
+        assert_eq!(proxy_type("url_test"), "URLTest");
+        assert_eq!(proxy_type("vless"), "Vless");
+    }
+
+    #[test]
+    fn test_from_browser() {
+        let mut headers = HeaderMap::new();
+        assert!(!from_browser(&headers, ""));
+
+        headers.insert(header::ORIGIN, "https://evil.example".parse().unwrap());
+        assert!(from_browser(&headers, ""));
+        assert!(!from_browser(&headers, "secret"));
+    }
+}
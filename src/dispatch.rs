@@ -3,174 +3,714 @@
 use std::{
     collections::HashMap,
     net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use kapibara_service::{
-    Address, InboundService, InboundServiceTrait, OutboundPacket, OutboundService,
-    OutboundServiceTrait, ServiceAddress,
-};
-use kapibara_transport::{
-    Resolver, TransportClient, TransportClientTrait, TransportServerCallback, TransportServerTrait,
+    Address, InboundService, InboundServiceTrait, OutboundPacket, ServiceAddress,
 };
+use kapibara_transport::{Resolver, TransportServerCallback, TransportServerTrait};
 use serde::{Deserialize, Serialize};
-use tokio::task::JoinHandle;
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle};
+use tracing::{field, Instrument, Span};
 
 use crate::{
+    access::{AccessLog, AccessLogOption, AccessRecord, AccessWriter, Termination},
+    api::{self, ApiOption, Control, InboundInfo, ReloadRequest},
+    clash::{self, ClashApiOption},
     dns::Dns,
+    drain::{Drain, DrainReport},
     error::OptionError,
-    io::{copy_bi, ToStreamTimer},
-    DispatchError, DnsOption, Inbound, InboundOption, Outbound, OutboundOption, Route, RouteOption,
+    group::GroupOption,
+    io::{copy_bi, Counter, Rewind},
+    logs::LogOption,
+    manager::{Dialed, OutboundManager},
+    metrics::{self, Metrics, MetricsOption, Stage},
+    sniff::sniff,
+    state::State,
+    stats::{Stats, StatsKey, StatsSnapshot},
+    tracker::{ConnInfo, ConnMeta, Tracker},
+    DispatchError, DnsOption, Inbound, InboundOption, OutboundError, OutboundOption, RejectOption,
+    Route, RouteContext, RouteOption, SniffOption,
 };
 
 const SERVER_RETRY: u8 = 30;
 
+// wait for access log writer to flush on shutdown
+const ACCESS_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DispatchOption {
     pub dns: Option<DnsOption>,
     pub route: RouteOption,
     pub inbound: Vec<InboundOption>,
     pub outbound: Vec<OutboundOption>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reject: Vec<RejectOption>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub group: Vec<GroupOption>,
+    // file to save runtime state, e.g. selected member of group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    // logging of binary, not changed by reload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<LogOption>,
+    // write a record per connection at close, not changed by reload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_log: Option<AccessLogOption>,
+    // serve prometheus metrics, not changed by reload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsOption>,
+    // serve json api, not changed by reload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api: Option<ApiOption>,
+    // serve clash external controller, not changed by reload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clash_api: Option<ClashApiOption>,
+    // wait for connections on shutdown, default 30s
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: Duration,
 }
 
+/// Parts replaced on reload, each connection uses the one
+/// current when it is accepted.
+pub struct Runtime {
+    pub resolver: Arc<Resolver>,
+    pub route: Arc<Route>,
+    pub outbound: Arc<OutboundManager>,
+    // option of route, for display
+    pub route_option: RouteOption,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReloadReport {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    pub kept: Vec<String>,
+}
+
+// dns, runtime, option of inbound with its json value
+type Built = (
+    Dns,
+    Runtime,
+    HashMap<String, (InboundOption, serde_json::Value)>,
+);
+
 pub struct Dispatch {
     dns: Dns,
-    route: Route,
+    runtime: Arc<RwLock<Arc<Runtime>>>,
     inbound: HashMap<String, Inbound>,
-    outbound: HashMap<String, Outbound>,
+    // option of running inbound, to find changed ones on reload
+    in_opt: HashMap<String, serde_json::Value>,
+    drain: Arc<Drain>,
+    tracker: Arc<Tracker>,
+    stats: Arc<Stats>,
+    metrics: Arc<Metrics>,
+    metrics_opt: Option<MetricsOption>,
+    access: Arc<AccessLog>,
+    access_writer: Option<AccessWriter>,
+    // finished after access log is closed
+    access_task: Option<JoinHandle<()>>,
+    api_opt: Option<ApiOption>,
+    clash_opt: Option<ClashApiOption>,
+    shutdown_timeout: Duration,
+    // shared with api
+    inbounds: Arc<RwLock<Vec<InboundInfo>>>,
+    ready: Arc<AtomicBool>,
+    reload_tx: mpsc::Sender<ReloadRequest>,
+    reload_rx: mpsc::Receiver<ReloadRequest>,
 
     in_state: HashMap<String, Option<JoinHandle<()>>>,
+    tasks: Vec<JoinHandle<()>>,
+    // local http services, kept across reload
+    services: Vec<JoinHandle<()>>,
 }
 
 impl Dispatch {
     pub fn init(option: DispatchOption) -> Result<Self, DispatchError> {
+        let shutdown_timeout = option.shutdown_timeout;
+        let metrics_opt = option.metrics.clone();
+        let api_opt = option.api.clone();
+        let clash_opt = option.clash_api.clone();
+        let (access, access_writer) = match option.access_log.clone() {
+            Some(opt) => {
+                let (log, writer) = AccessLog::open(opt)?;
+                (log, Some(writer))
+            }
+            None => (AccessLog::default(), None),
+        };
+        let (dns, runtime, inbound) = Self::build(option, None)?;
+
+        let mut in_opt = HashMap::new();
+        let mut inbound_map = HashMap::new();
+        for (tag, (i, opt)) in inbound {
+            inbound_map.insert(tag.to_owned(), Inbound::init(i)?);
+            in_opt.insert(tag, opt);
+        }
+
+        let (reload_tx, reload_rx) = mpsc::channel(1);
+
+        Ok(Self {
+            dns,
+            runtime: Arc::new(RwLock::new(Arc::new(runtime))),
+            inbound: inbound_map,
+            in_opt,
+            drain: Arc::default(),
+            tracker: Arc::default(),
+            stats: Arc::default(),
+            metrics: Arc::default(),
+            metrics_opt,
+            access: Arc::new(access),
+            access_writer,
+            access_task: None,
+            api_opt,
+            clash_opt,
+            shutdown_timeout,
+            inbounds: Arc::default(),
+            ready: Arc::default(),
+            reload_tx,
+            reload_rx,
+
+            in_state: HashMap::new(),
+            tasks: vec![],
+            services: vec![],
+        })
+    }
+
+    // old outbound manager passes its runtime state to the new one,
+    // inbounds are not initialized so no listener is bound yet
+    fn build(
+        option: DispatchOption,
+        old: Option<&OutboundManager>,
+    ) -> Result<Built, DispatchError> {
         let dns = Dns::init(option.dns)?;
 
+        let route_option = option.route.clone();
         let route = Route::init(option.route)?;
 
         let mut inbound = HashMap::new();
         for in_opt in option.inbound {
-            let i = Inbound::init(in_opt)?;
-            if let Some(other) = inbound.insert(i.tag().to_owned(), i) {
-                return Err(DispatchError::Option(OptionError::DuplicateTag(
-                    other.tag().to_owned(),
+            let opt = serde_json::to_value(&in_opt).unwrap_or_default();
+            let tag = in_opt.tag.to_owned();
+            if inbound.insert(tag.to_owned(), (in_opt, opt)).is_some() {
+                return Err(DispatchError::Option(OptionError::DuplicateTag(tag)));
+            }
+        }
+
+        let state = Arc::new(State::load(option.state));
+        let mut outbound = OutboundManager::init(
+            option.outbound,
+            option.reject,
+            option.group,
+            dns.resolver(),
+            state,
+        )?;
+        if let Some(old) = old {
+            outbound.inherit(old);
+        }
+
+        for in_tag in route.inbounds() {
+            if !inbound.contains_key(in_tag) {
+                return Err(DispatchError::Option(OptionError::UnknownTag(
+                    in_tag.to_owned(),
                 )));
             }
         }
 
-        let mut outbound = HashMap::new();
-        for out_opt in option.outbound {
-            let o = Outbound::init(out_opt, dns.resolver())?;
-            if let Some(other) = outbound.insert(o.tag().to_owned(), o) {
-                return Err(DispatchError::Option(OptionError::DuplicateTag(
-                    other.tag().to_owned(),
+        for out_tag in route.outbounds() {
+            if !outbound.contains(out_tag) {
+                return Err(DispatchError::Option(OptionError::UnknownTag(
+                    out_tag.to_owned(),
                 )));
             }
         }
 
-        Ok(Self {
-            dns,
-            route,
-            inbound,
-            outbound,
+        let runtime = Runtime {
+            resolver: dns.get_resolver(),
+            route: Arc::new(route),
+            outbound: Arc::new(outbound),
+            route_option,
+        };
 
-            in_state: HashMap::new(),
-        })
+        Ok((dns, runtime, inbound))
     }
 
     pub fn start(&mut self) -> Result<(), DispatchError> {
-        for (in_tag, rule) in self.route.in_to_out.iter() {
-            let inbound =
-                self.inbound
-                    .get(in_tag)
-                    .ok_or(DispatchError::Option(OptionError::UnknownTag(
-                        in_tag.to_owned(),
-                    )))?;
-
-            let outbound = self
-                .outbound
-                .get(&rule.outbound)
-                .ok_or(DispatchError::Option(OptionError::UnknownTag(
-                    rule.outbound.to_owned(),
-                )))?;
+        if let Some(writer) = self.access_writer.take() {
+            self.access_task = Some(tokio::spawn(writer.run()));
+        }
 
-            let resolver = if rule.dns {
-                Some(self.dns.get_resolver())
-            } else {
-                None
-            };
+        if let Some(ref opt) = self.metrics_opt {
+            let listener = bind(opt.listen)?;
+            tracing::info!("[metrics] listen {}", opt.listen);
+            self.services.push(tokio::spawn(metrics::serve(
+                listener,
+                self.metrics.clone(),
+                self.drain.clone(),
+                self.tracker.clone(),
+                self.stats.clone(),
+            )));
+        }
+
+        if let Some(ref opt) = self.api_opt {
+            if opt.token.is_empty() {
+                return Err(DispatchError::Option(OptionError::Invalid(
+                    "empty api token".to_owned(),
+                )));
+            }
+            let listener = bind(opt.listen)?;
+            tracing::info!("[api] listen {}", opt.listen);
+            self.services.push(tokio::spawn(api::serve(
+                listener,
+                opt.token.to_owned(),
+                self.control(),
+            )));
+        }
+
+        if let Some(ref opt) = self.clash_opt {
+            if opt.secret.is_empty() {
+                if !opt.listen.ip().is_loopback() {
+                    return Err(DispatchError::Option(OptionError::Invalid(
+                        "empty clash_api secret on non loopback address".to_owned(),
+                    )));
+                }
+                tracing::warn!("[clash_api] no secret, requests from browsers are refused");
+            }
+            let listener = bind(opt.listen)?;
+            tracing::info!("[clash_api] listen {}", opt.listen);
+            self.services.push(tokio::spawn(clash::serve(
+                listener,
+                opt.secret.to_owned(),
+                self.control(),
+            )));
+        }
 
-            let server = inbound.get_server();
+        self.spawn_tasks();
 
-            log::info!(
-                "[inbound] start {} server {}",
-                server.name(),
-                if let Some(addr) = server.local_addr() {
-                    addr.to_string()
-                } else {
-                    "".to_string()
+        let tags: Vec<String> = self.inbound.keys().cloned().collect();
+        for in_tag in tags {
+            self.start_inbound(&in_tag)?;
+        }
+        self.update_inbounds();
+        self.ready.store(true, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// State shared with local control services.
+    pub fn control(&self) -> Arc<Control> {
+        Arc::new(Control {
+            runtime: self.runtime.clone(),
+            inbounds: self.inbounds.clone(),
+            ready: self.ready.clone(),
+            tracker: self.tracker.clone(),
+            stats: self.stats.clone(),
+            reload: self.reload_tx.clone(),
+        })
+    }
+
+    /// Wait for a reload asked by api. The caller applies it
+    /// with `reload` and sends back the result, if nobody polls
+    /// the api answers 503. Requests already given up by the
+    /// api are dropped without reload.
+    pub async fn reload_requested(&mut self) -> ReloadRequest {
+        loop {
+            match self.reload_rx.recv().await {
+                Some(req) if req.is_closed() => {
+                    tracing::debug!("[dispatch] drop reload request timed out");
                 }
+                Some(req) => return req,
+                // sender is held by self, never closed
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    fn update_inbounds(&self) {
+        let mut list: Vec<InboundInfo> = self
+            .inbound
+            .iter()
+            .map(|(tag, i)| {
+                let server = i.get_server();
+                InboundInfo {
+                    tag: tag.to_owned(),
+                    service: i.get_service().name().to_owned(),
+                    transport: server.name().to_owned(),
+                    listen: server.local_addr(),
+                    running: matches!(self.in_state.get(tag), Some(Some(_))),
+                }
+            })
+            .collect();
+        list.sort_by(|a, b| a.tag.cmp(&b.tag));
+
+        *self.inbounds.write().unwrap() = list;
+    }
+
+    // rule set watchers and group probes of current runtime
+    fn spawn_tasks(&mut self) {
+        let runtime = self.runtime();
+
+        for rule_set in runtime.route.rule_set.iter() {
+            tracing::info!(
+                "[route] watch rule set [{}] {}",
+                rule_set.tag(),
+                rule_set.path().display()
             );
+            self.tasks.push(tokio::spawn(rule_set.clone().watch()));
+        }
 
-            let callback = DispatchCallback::new(inbound, outbound, resolver);
-            let task = tokio::spawn(async move {
-                for i in 0..SERVER_RETRY {
-                    if let Err(e) = server.serve(callback.clone()).await {
-                        if i < SERVER_RETRY - 1 {
-                            log::error!("[inbound] <server> {}", e);
-                        } else {
-                            panic!("[inbound] <server> {}", e);
-                        }
+        self.tasks.extend(runtime.outbound.spawn());
+    }
+
+    fn start_inbound(&mut self, in_tag: &str) -> Result<(), DispatchError> {
+        let Some(inbound) = self.inbound.get(in_tag) else {
+            return Err(DispatchError::Option(OptionError::UnknownTag(
+                in_tag.to_owned(),
+            )));
+        };
+        let server = inbound.get_server();
+
+        tracing::info!(
+            "[inbound] start {} server {}",
+            server.name(),
+            if let Some(addr) = server.local_addr() {
+                addr.to_string()
+            } else {
+                "".to_string()
+            }
+        );
+
+        let callback = DispatchCallback::new(
+            inbound,
+            self.runtime.clone(),
+            self.drain.clone(),
+            self.tracker.clone(),
+            self.stats.clone(),
+            self.metrics.clone(),
+            self.access.clone(),
+        );
+        let task = tokio::spawn(async move {
+            for i in 0..SERVER_RETRY {
+                if let Err(e) = server.serve(callback.clone()).await {
+                    if i < SERVER_RETRY - 1 {
+                        tracing::error!("[inbound] <server> {}", e);
+                    } else {
+                        panic!("[inbound] <server> {}", e);
                     }
                 }
-            });
-
-            if let Some(_) = self.in_state.insert(in_tag.to_owned(), Some(task)) {
-                return Err(DispatchError::Option(OptionError::DuplicateTag(
-                    in_tag.to_owned(),
-                )));
             }
+        });
+
+        if let Some(Some(other)) = self.in_state.insert(in_tag.to_owned(), Some(task)) {
+            other.abort();
+            return Err(DispatchError::Option(OptionError::DuplicateTag(
+                in_tag.to_owned(),
+            )));
         }
 
         Ok(())
     }
 
+    /// Apply new option: inbounds with changed option are restarted,
+    /// others keep running. New connections use new route and outbounds,
+    /// existing ones are left alone. If a new inbound fails to start,
+    /// the started ones are stopped and the previous inbounds and
+    /// runtime are restored.
+    pub async fn reload(&mut self, option: DispatchOption) -> Result<ReloadReport, DispatchError> {
+        let shutdown_timeout = option.shutdown_timeout;
+        let prev = self.runtime();
+        let (dns, runtime, mut inbound) = Self::build(option, Some(&prev.outbound))?;
+
+        let new_opt = inbound
+            .iter()
+            .map(|(tag, (_, opt))| (tag.to_owned(), opt.clone()))
+            .collect();
+        let report = diff_inbounds(&self.in_opt, &new_opt);
+
+        // stop removed and changed inbounds before their replacement
+        // is initialized, so the listen address is released
+        let mut stopped = vec![];
+        for tag in report.stopped.iter() {
+            self.remove_inbound(tag).await;
+            if let Some(opt) = self.in_opt.remove(tag) {
+                stopped.push((tag.to_owned(), opt));
+            }
+        }
+
+        *self.runtime.write().unwrap() = Arc::new(runtime);
+
+        for (n, tag) in report.started.iter().enumerate() {
+            let Some((i, opt)) = inbound.remove(tag) else {
+                continue;
+            };
+            if let Err(e) = self.add_inbound(i, opt) {
+                tracing::error!("[inbound]({}) start failed, restore running inbounds", tag);
+                for tag in report.started[..n].iter() {
+                    self.remove_inbound(tag).await;
+                    self.in_opt.remove(tag);
+                }
+                for (tag, opt) in stopped {
+                    self.restore_inbound(&tag, opt);
+                }
+                *self.runtime.write().unwrap() = prev;
+                self.update_inbounds();
+                return Err(e);
+            }
+        }
+
+        self.dns = dns;
+        self.shutdown_timeout = shutdown_timeout;
+
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.spawn_tasks();
+        self.update_inbounds();
+
+        Ok(report)
+    }
+
+    fn add_inbound(
+        &mut self,
+        in_opt: InboundOption,
+        opt: serde_json::Value,
+    ) -> Result<(), DispatchError> {
+        let tag = in_opt.tag.to_owned();
+        self.inbound.insert(tag.to_owned(), Inbound::init(in_opt)?);
+        self.in_opt.insert(tag.to_owned(), opt);
+        self.start_inbound(&tag)
+    }
+
+    // stop inbound and wait, so its listener is released
+    async fn remove_inbound(&mut self, tag: &str) {
+        if let Some(Some(h)) = self.in_state.remove(tag) {
+            h.abort();
+            let _ = h.await;
+        }
+        self.inbound.remove(tag);
+        tracing::info!("[inbound]({}) closed", tag);
+    }
+
+    // start inbound again from the option it was running with
+    fn restore_inbound(&mut self, tag: &str, opt: serde_json::Value) {
+        let result = serde_json::from_value::<InboundOption>(opt.clone())
+            .map_err(|e| DispatchError::Option(OptionError::Deserialize(e.to_string())))
+            .and_then(|in_opt| self.add_inbound(in_opt, opt));
+        if let Err(e) = result {
+            tracing::error!("[inbound]({}) restore failed: {}", tag, e);
+        }
+    }
+
     pub fn close(&mut self) {
+        self.stop_inbound();
+        self.drain.cancel();
+
+        for task in self.tasks.drain(..).chain(self.services.drain(..)) {
+            task.abort();
+        }
+
+        // writer keeps running to flush what is queued
+        self.access.close();
+        self.access_task.take();
+    }
+
+    /// Stop accepting, wait for connections to finish until
+    /// shutdown timeout, then close the rest.
+    pub async fn shutdown(&mut self) -> DrainReport {
+        self.stop_inbound();
+
+        tracing::info!(
+            "[dispatch] draining {} connections, timeout {}s",
+            self.drain.active(),
+            self.shutdown_timeout.as_secs()
+        );
+        let report = self.drain.drain(self.shutdown_timeout).await;
+
+        for task in self.tasks.drain(..).chain(self.services.drain(..)) {
+            task.abort();
+        }
+
+        // records of drained and killed connections are queued by now
+        self.access.close();
+        if let Some(mut task) = self.access_task.take() {
+            if tokio::time::timeout(ACCESS_FLUSH_TIMEOUT, &mut task)
+                .await
+                .is_err()
+            {
+                tracing::warn!("[access] flush timeout");
+                task.abort();
+            }
+        }
+
+        report
+    }
+
+    fn stop_inbound(&mut self) {
+        self.ready.store(false, Ordering::Relaxed);
+
         for state in self.in_state.iter_mut() {
             if let Some(h) = state.1.take() {
-                log::info!("[inbound]({}) closed", state.0);
+                tracing::info!("[inbound]({}) closed", state.0);
                 h.abort();
             }
         }
+        self.update_inbounds();
+    }
+
+    /// Number of in-flight connections.
+    pub fn active(&self) -> usize {
+        self.drain.active()
+    }
+
+    pub fn tracker(&self) -> Arc<Tracker> {
+        self.tracker.clone()
+    }
+
+    pub fn connections(&self) -> Vec<ConnInfo> {
+        self.tracker.list()
+    }
+
+    /// Kill connection by id, return false if not found.
+    pub fn kill(&self, id: u64) -> bool {
+        self.tracker.kill(id)
+    }
+
+    /// Kill all connections of user, return the number killed.
+    pub fn kill_user(&self, user: &str) -> usize {
+        self.tracker.kill_user(user)
+    }
+
+    /// Traffic of closed connections.
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Return traffic stats and set them to zero.
+    pub fn reset_stats(&self) -> StatsSnapshot {
+        self.stats.reset()
+    }
+
+    pub fn runtime(&self) -> Arc<Runtime> {
+        self.runtime.read().unwrap().clone()
+    }
+
+    pub fn outbound(&self) -> Arc<OutboundManager> {
+        self.runtime().outbound.clone()
+    }
+
+    /// Switch the member of selector group, new connections use it.
+    pub fn select(&self, group: &str, member: &str) -> Result<(), DispatchError> {
+        Ok(self.runtime().outbound.select(group, member)?)
     }
 }
 
 #[derive(Clone)]
 pub struct DispatchCallback {
-    resolver: Option<Arc<Resolver>>,
+    runtime: Arc<RwLock<Arc<Runtime>>>,
 
     in_tag: String,
     in_svc: Arc<InboundService>,
+    sniff: Option<SniffOption>,
 
-    out_tag: String,
-    out_svc: Arc<OutboundService>,
-    out_cli: Arc<TransportClient>,
-    timeout: Option<Duration>,
+    drain: Arc<Drain>,
+    tracker: Arc<Tracker>,
+    stats: Arc<Stats>,
+    metrics: Arc<Metrics>,
+    access: Arc<AccessLog>,
 }
 
 impl DispatchCallback {
-    pub fn new(inbound: &Inbound, outbound: &Outbound, resolver: Option<Arc<Resolver>>) -> Self {
+    pub fn new(
+        inbound: &Inbound,
+        runtime: Arc<RwLock<Arc<Runtime>>>,
+        drain: Arc<Drain>,
+        tracker: Arc<Tracker>,
+        stats: Arc<Stats>,
+        metrics: Arc<Metrics>,
+        access: Arc<AccessLog>,
+    ) -> Self {
         Self {
-            resolver,
+            runtime,
             in_tag: inbound.get_tag(),
             in_svc: inbound.get_service(),
-            out_tag: outbound.get_tag(),
-            out_svc: outbound.get_service(),
-            out_cli: outbound.get_client(),
-            timeout: outbound.timeout(),
+            sniff: inbound.sniff().cloned(),
+            drain,
+            tracker,
+            stats,
+            metrics,
+            access,
         }
     }
+
+    async fn resolve(&self, rt: &Runtime, domain: &str, port: u16) -> Option<SocketAddr> {
+        let start = Instant::now();
+        let resolved = rt
+            .resolver
+            .resolve(domain, port)
+            .instrument(tracing::debug_span!("dns", domain))
+            .await;
+        self.metrics.observe_dns(start.elapsed());
+
+        let mut resolved = match resolved {
+            Ok(r) => r,
+            Err(e) => {
+                tracing::debug!("[dns] <resolve> {}", e);
+                self.metrics.add_failure(Stage::DnsResolve);
+                return None;
+            }
+        };
+
+        let addr = resolved.next();
+        if addr.is_none() {
+            tracing::debug!("[dns] <resolve> empty resolved");
+            self.metrics.add_failure(Stage::DnsResolve);
+        }
+
+        addr
+    }
+}
+
+// compare option of running inbounds with new ones, a changed inbound
+// is stopped and started again, a renamed one is a removed and a new one
+fn diff_inbounds(
+    running: &HashMap<String, serde_json::Value>,
+    new: &HashMap<String, serde_json::Value>,
+) -> ReloadReport {
+    let mut report = ReloadReport::default();
+
+    for (tag, opt) in running {
+        if new.get(tag) != Some(opt) {
+            report.stopped.push(tag.to_owned());
+        }
+    }
+
+    for (tag, opt) in new {
+        if running.get(tag) == Some(opt) {
+            report.kept.push(tag.to_owned());
+        } else {
+            report.started.push(tag.to_owned());
+        }
+    }
+
+    report.stopped.sort();
+    report.started.sort();
+    report.kept.sort();
+    report
+}
+
+// bind in sync context, so error is returned by start
+fn bind(addr: SocketAddr) -> Result<TcpListener, DispatchError> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(listener)?)
 }
 
 const UNSPECIFIED_ADDRESS: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
@@ -180,106 +720,448 @@ impl TransportServerCallback for DispatchCallback {
     where
         S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
     {
-        let (mut in_stream, in_pac) = match self.in_svc.handshake(stream).await {
+        let _guard = self.drain.guard();
+        self.metrics.add_connection();
+
+        // id, dest and outbound are recorded once known
+        let span = tracing::info_span!(
+            "conn",
+            id = field::Empty,
+            inbound = %self.in_tag,
+            source = %addr.unwrap_or(UNSPECIFIED_ADDRESS),
+            dest = field::Empty,
+            outbound = field::Empty,
+        );
+
+        // dispatch first, so a relaying connection records its shutdown
+        tokio::select! {
+            biased;
+            _ = self.dispatch(stream, addr).instrument(span) => {}
+            _ = self.drain.cancelled() => {
+                tracing::debug!(
+                    "[dispatch] {}[{}] connection killed",
+                    self.in_svc.name(),
+                    self.in_tag
+                );
+            }
+        }
+    }
+}
+
+impl DispatchCallback {
+    async fn dispatch<S>(&self, stream: S, addr: Option<SocketAddr>)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + Sync,
+    {
+        let rt = self.runtime.read().unwrap().clone();
+        let start = Instant::now();
+
+        let (in_stream, mut in_pac) = match self
+            .in_svc
+            .handshake(stream)
+            .instrument(tracing::debug_span!("handshake"))
+            .await
+        {
             Ok((s, p)) => (s, p),
             Err(e) => {
-                log::debug!("[inbound] {}", e);
+                tracing::debug!("[inbound] {}", e);
+                self.metrics.add_failure(Stage::InboundHandshake);
                 return;
             }
         };
 
-        log::info!(
-            "[dispatch] {}[{}] -> {}[{}] [{}]({}) {}://{}",
-            self.in_svc.name(),
-            self.in_tag,
-            self.out_svc.name(),
-            self.out_tag,
-            in_pac.detail,
-            if let Some(a) = addr {
-                a
-            } else {
-                UNSPECIFIED_ADDRESS
-            },
-            in_pac.typ,
-            in_pac.dest
-        );
+        let original_dest = in_pac.dest.to_string();
+        Span::current().record("dest", &original_dest);
+        let mut sniffed_domain = None;
+
+        // sniffed bytes are replayed by rewind stream
+        let mut in_stream = Rewind::new(in_stream);
+        let sniffed = match self.sniff {
+            Some(ref opt) if matches!(in_pac.dest.addr, Address::Socket(_)) => {
+                let (s, domain) = sniff(in_stream, opt.timeout)
+                    .instrument(tracing::debug_span!("sniff"))
+                    .await;
+                in_stream = s;
+
+                if let Some(ref domain) = domain {
+                    tracing::debug!("[sniff] {} -> {}", in_pac.dest, domain);
+                }
+                sniffed_domain.clone_from(&domain);
 
-        let dest = if let Some(ref resolver) = self.resolver {
-            match in_pac.dest.addr {
-                Address::Domain(domain) => {
-                    let mut resolved = match resolver.resolve(&domain, in_pac.dest.port).await {
-                        Ok(r) => r,
-                        Err(e) => {
-                            log::debug!("[dns] <resolve> {}", e);
-                            return;
-                        }
-                    };
-
-                    let addr = match resolved.next() {
-                        Some(a) => a,
-                        None => {
-                            log::debug!("[dns] <resolve> empty resolved");
-                            return;
-                        }
-                    };
-
-                    ServiceAddress::new(Address::Socket(addr.ip()), addr.port())
+                match domain {
+                    Some(domain) if opt.override_dest => {
+                        in_pac.dest =
+                            ServiceAddress::new(Address::Domain(domain), in_pac.dest.port);
+                        None
+                    }
+                    other => other,
                 }
-                Address::Socket(_) => in_pac.dest,
             }
-        } else {
-            in_pac.dest
+            _ => None,
         };
 
-        let out_pac = OutboundPacket {
-            typ: in_pac.typ,
-            dest,
+        let user = in_pac.detail.to_string();
+        let mut ctx = RouteContext {
+            inbound: &self.in_tag,
+            dest: &in_pac.dest,
+            user: if user.is_empty() { None } else { Some(&user) },
+            source: addr,
+            sniffed: sniffed.as_deref(),
+            resolved: None,
         };
 
-        let cli_stream = match self.out_cli.connect().await {
-            Ok(s) => s,
-            Err(e) => {
-                log::debug!("[outbound] <client> {}", e);
+        if let Address::Domain(ref domain) = in_pac.dest.addr {
+            if rt.route.resolve_first() {
+                ctx.resolved = self
+                    .resolve(&rt, domain, in_pac.dest.port)
+                    .await
+                    .map(|a| a.ip());
+            }
+        }
+
+        let mut decision = rt.route.decide(&ctx);
+
+        if let Address::Domain(ref domain) = in_pac.dest.addr {
+            let matched = decision.is_some_and(|d| d.index.is_some());
+            if !matched && ctx.resolved.is_none() && rt.route.resolve_non_match() {
+                ctx.resolved = self
+                    .resolve(&rt, domain, in_pac.dest.port)
+                    .await
+                    .map(|a| a.ip());
+                if ctx.resolved.is_some() {
+                    decision = rt.route.decide(&ctx);
+                }
+            }
+        }
+
+        let resolved = ctx.resolved;
+        let decision = match decision {
+            Some(d) => d,
+            None => {
+                tracing::debug!("[route] no rule for [{}] {}", self.in_tag, in_pac.dest);
+                self.metrics.add_failure(Stage::Route);
                 return;
             }
         };
 
-        // if cli_stream is empty, so the timer need to set after handshake
-        // else cli_stream need to set timer first, because handshake need.
-        if cli_stream.is_emtpy() {
-            let mut out_stream = match self.out_svc.handshake(cli_stream, out_pac).await {
-                Ok(s) => s.to_timer(self.timeout),
-                Err(e) => {
-                    log::debug!("[outbound] {}", e);
-                    return;
+        let conn = self.tracker.track(ConnMeta {
+            inbound: self.in_tag.to_owned(),
+            outbound: decision.outbound.to_owned(),
+            rule: decision.to_string(),
+            user: if user.is_empty() {
+                None
+            } else {
+                Some(user.to_owned())
+            },
+            source: addr,
+            network: in_pac.typ.to_string(),
+            dest: in_pac.dest.to_string(),
+        });
+        Span::current()
+            .record("id", conn.id())
+            .record("outbound", decision.outbound);
+        let (upload, download) = conn.counter();
+        let mut in_stream = Counter::new(in_stream, upload, download);
+
+        // address dialed for domain destination
+        let mut dialed_addr = resolved.map(|ip| SocketAddr::new(ip, in_pac.dest.port));
+
+        let relay = async {
+            if let Some(reject) = rt.outbound.reject(decision.outbound) {
+                tracing::debug!(
+                    "[dispatch] #{} {}[{}] -> reject[{}] ({}) [{}]({}) {}://{}",
+                    conn.id(),
+                    self.in_svc.name(),
+                    self.in_tag,
+                    reject.tag(),
+                    decision,
+                    user,
+                    if let Some(a) = addr {
+                        a
+                    } else {
+                        UNSPECIFIED_ADDRESS
+                    },
+                    in_pac.typ,
+                    in_pac.dest
+                );
+
+                reject.reject(in_stream).await;
+                return Ok(Termination::Rejected);
+            }
+
+            let out_name = match rt.outbound.name(decision.outbound) {
+                Some(n) => n,
+                None => {
+                    tracing::debug!("[route] unknown outbound [{}]", decision.outbound);
+                    self.metrics.add_failure(Stage::Route);
+                    return Err(format!("[route] unknown outbound [{}]", decision.outbound));
                 }
             };
 
-            let (_tx, _rx) = match copy_bi(&mut in_stream, &mut out_stream).await {
-                Ok(s) => s,
-                Err(e) => {
-                    log::debug!("[transport] {}", e);
-                    return;
+            tracing::debug!(
+                "[dispatch] #{} {}[{}] -> {}[{}] ({}) [{}]({}) {}://{}",
+                conn.id(),
+                self.in_svc.name(),
+                self.in_tag,
+                out_name,
+                decision.outbound,
+                decision,
+                user,
+                if let Some(a) = addr {
+                    a
+                } else {
+                    UNSPECIFIED_ADDRESS
+                },
+                in_pac.typ,
+                in_pac.dest
+            );
+
+            let dest = if decision.dns {
+                match in_pac.dest.addr {
+                    Address::Domain(domain) => {
+                        let addr = match dialed_addr {
+                            Some(a) => a,
+                            None => match self.resolve(&rt, &domain, in_pac.dest.port).await {
+                                Some(a) => a,
+                                None => return Err(format!("[dns] resolve {} failed", domain)),
+                            },
+                        };
+                        dialed_addr = Some(addr);
+
+                        ServiceAddress::new(Address::Socket(addr.ip()), addr.port())
+                    }
+                    Address::Socket(_) => in_pac.dest,
                 }
+            } else {
+                in_pac.dest
             };
-        } else {
-            let cli_stream = cli_stream.to_timer(self.timeout);
 
-            let mut out_stream = match self.out_svc.handshake(cli_stream, out_pac).await {
-                Ok(s) => s,
+            let out_pac = OutboundPacket {
+                typ: in_pac.typ,
+                dest,
+            };
+
+            let mut out_stream = match rt
+                .outbound
+                .dial(decision.outbound, out_pac, addr)
+                .instrument(tracing::debug_span!("connect"))
+                .await
+            {
+                Ok(Dialed::Stream { tag, stream }) => {
+                    conn.set_dialed(&tag);
+                    stream
+                }
+                Ok(Dialed::Reject(reject)) => {
+                    reject.reject(in_stream).await;
+                    return Ok(Termination::Rejected);
+                }
                 Err(e) => {
-                    log::debug!("[outbound] {}", e);
-                    return;
+                    tracing::debug!("[outbound] {}", e);
+                    self.metrics.add_failure(match e {
+                        OutboundError::Client(_) => Stage::ClientConnect,
+                        OutboundError::Service(_) => Stage::OutboundHandshake,
+                        _ => Stage::Outbound,
+                    });
+                    return Err(format!("[outbound] {}", e));
                 }
             };
 
-            let (_tx, _rx) = match copy_bi(&mut in_stream, &mut out_stream).await {
-                Ok(s) => s,
+            match copy_bi(&mut in_stream, &mut out_stream)
+                .instrument(tracing::debug_span!("relay"))
+                .await
+            {
+                Ok((tx, rx)) => {
+                    tracing::debug!("[dispatch] #{} closed, up {} down {}", conn.id(), tx, rx);
+                    Ok(Termination::Closed)
+                }
                 Err(e) => {
-                    log::debug!("[transport] {}", e);
-                    return;
+                    tracing::debug!("[transport] {}", e);
+                    self.metrics.add_failure(Stage::Relay);
+                    Err(format!("[transport] {}", e))
                 }
-            };
+            }
+        };
+
+        let result = tokio::select! {
+            r = relay => r,
+            _ = conn.killed() => {
+                tracing::debug!("[dispatch] connection #{} killed", conn.id());
+                Ok(Termination::Killed)
+            }
+            _ = self.drain.cancelled() => Ok(Termination::Shutdown),
+        };
+
+        let info = conn.info();
+        let mut outbound = vec![info.outbound.as_str()];
+        if let Some(ref dialed) = info.dialed {
+            if *dialed != info.outbound {
+                outbound.push(dialed);
+            }
+        }
+        self.stats.record(
+            &StatsKey {
+                inbound: &info.inbound,
+                outbound: &outbound,
+                user: info.user.as_deref(),
+            },
+            info.upload,
+            info.download,
+        );
+
+        let (termination, error) = match result {
+            Ok(t) => (t, None),
+            Err(e) => (Termination::Error, Some(e)),
+        };
+        self.access.log(&AccessRecord {
+            time: SystemTime::now(),
+            id: info.id,
+            inbound: info.inbound,
+            outbound: info.outbound,
+            dialed: info.dialed,
+            rule: info.rule,
+            user: info.user,
+            source: info.source,
+            network: info.network,
+            dest: original_dest,
+            sniffed: sniffed_domain,
+            resolved: dialed_addr,
+            upload: info.upload,
+            download: info.download,
+            duration: start.elapsed(),
+            termination,
+            error,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn record(id: u64) -> AccessRecord {
+        AccessRecord {
+            time: SystemTime::now(),
+            id,
+            inbound: "in".into(),
+            outbound: "out".into(),
+            dialed: None,
+            rule: "final".into(),
+            user: None,
+            source: None,
+            network: "tcp".into(),
+            dest: "example.com:443".into(),
+            sniffed: None,
+            resolved: None,
+            upload: 0,
+            download: 0,
+            duration: Duration::ZERO,
+            termination: Termination::Shutdown,
+            error: None,
+        }
+    }
+
+    #[test]
+    fn test_diff_inbounds() {
+        let map = |list: &[(&str, u16)]| -> HashMap<String, serde_json::Value> {
+            list.iter()
+                .map(|(tag, port)| (tag.to_string(), json!({ "tag": tag, "port": port })))
+                .collect()
+        };
+        let running = map(&[("a", 1), ("b", 2), ("c", 3), ("d", 4)]);
+
+        // a unchanged, b changed, c renamed to e, d removed
+        let new = map(&[("a", 1), ("b", 20), ("e", 3)]);
+        let report = diff_inbounds(&running, &new);
+        assert_eq!(report.kept, vec!["a"]);
+        assert_eq!(report.started, vec!["b", "e"]);
+        assert_eq!(report.stopped, vec!["b", "c", "d"]);
+
+        let report = diff_inbounds(&running, &running);
+        assert_eq!(report.kept, vec!["a", "b", "c", "d"]);
+        assert!(report.started.is_empty() && report.stopped.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reload_request() {
+        let option = serde_json::from_value(json!({
+            "route": {},
+            "inbound": [],
+            "outbound": [],
+        }))
+        .unwrap();
+        let mut dispatch = Dispatch::init(option).unwrap();
+        let control = dispatch.control();
+
+        let (result, _) = tokio::join!(control.reload(), async {
+            let req = dispatch.reload_requested().await;
+            let _ = req.send(Ok(ReloadReport::default()));
+        });
+        assert!(matches!(result, Some(Ok(_))));
+
+        // nobody polls, times out, then the pending request fills the queue
+        assert!(control.reload().await.is_none());
+        assert!(control.reload().await.is_none());
+
+        // timed out request is dropped, not reloaded later
+        let stale = tokio::time::timeout(Duration::from_secs(1), dispatch.reload_requested()).await;
+        assert!(stale.is_err());
+
+        let (result, _) = tokio::join!(control.reload(), async {
+            let req = dispatch.reload_requested().await;
+            let _ = req.send(Ok(ReloadReport::default()));
+        });
+        assert!(matches!(result, Some(Ok(_))));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_access_log() {
+        let dir = std::env::temp_dir().join(format!("kapibara-dispatch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let option = serde_json::from_value(json!({
+            "route": {},
+            "inbound": [],
+            "outbound": [],
+            "access_log": { "path": path },
+            "shutdown_timeout": { "secs": 0, "nanos": 100_000_000 },
+        }))
+        .unwrap();
+        let mut dispatch = Dispatch::init(option).unwrap();
+        dispatch.start().unwrap();
+
+        // connections log their record just before the guard drops,
+        // half finish while draining, the rest are killed at deadline
+        for id in 0..100 {
+            let guard = dispatch.drain.guard();
+            let drain = dispatch.drain.clone();
+            let access = dispatch.access.clone();
+            tokio::spawn(async move {
+                if id % 2 == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                } else {
+                    drain.cancelled().await;
+                }
+                access.log(&record(id));
+                drop(guard);
+            });
         }
+
+        let report = dispatch.shutdown().await;
+        assert_eq!(
+            report,
+            DrainReport {
+                drained: 50,
+                killed: 50
+            }
+        );
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 100);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
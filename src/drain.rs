@@ -0,0 +1,152 @@
+//! Kapibara Drain
+//!
+//! Count in-flight connections, wait for them to finish on shutdown,
+//! and cancel the rest after deadline.
+
+use std::{
+    pin::pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{watch, Notify};
+
+// wait for cancelled connections to drop
+const CANCEL_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    // finished before deadline
+    pub drained: usize,
+    // cancelled at deadline
+    pub killed: usize,
+}
+
+#[derive(Debug)]
+pub struct Drain {
+    active: AtomicUsize,
+    idle: Notify,
+    cancel: watch::Sender<bool>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self {
+            active: AtomicUsize::new(0),
+            idle: Notify::new(),
+            cancel: watch::channel(false).0,
+        }
+    }
+}
+
+impl Drain {
+    /// Count a connection until the guard dropped.
+    pub fn guard(self: &Arc<Self>) -> DrainGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        DrainGuard(self.clone())
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    /// Cancel all connections.
+    pub fn cancel(&self) {
+        self.cancel.send_replace(true);
+    }
+
+    /// Resolve once cancel is called.
+    pub async fn cancelled(&self) {
+        let mut rx = self.cancel.subscribe();
+        let _ = rx.wait_for(|c| *c).await;
+    }
+
+    /// Wait for connections to finish until timeout, then cancel the rest.
+    pub async fn drain(&self, timeout: Duration) -> DrainReport {
+        let total = self.active();
+
+        let _ = tokio::time::timeout(timeout, self.idle()).await;
+
+        let killed = self.active();
+        if killed > 0 {
+            self.cancel();
+            let _ = tokio::time::timeout(CANCEL_WAIT, self.idle()).await;
+        }
+
+        DrainReport {
+            drained: total.saturating_sub(killed),
+            killed,
+        }
+    }
+
+    async fn idle(&self) {
+        loop {
+            // register before check, so a drop in between is not missed
+            let mut notified = pin!(self.idle.notified());
+            notified.as_mut().enable();
+
+            if self.active() == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+pub struct DrainGuard(Arc<Drain>);
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_drain() {
+        let drain = Arc::new(Drain::default());
+
+        // finish in time
+        let guard = drain.guard();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(guard);
+        });
+
+        // never finish unless cancelled
+        let guard = drain.guard();
+        let d = drain.clone();
+        tokio::spawn(async move {
+            let _guard = guard;
+            d.cancelled().await;
+        });
+
+        assert_eq!(drain.active(), 2);
+        let report = drain.drain(Duration::from_millis(100)).await;
+        assert_eq!(
+            report,
+            DrainReport {
+                drained: 1,
+                killed: 1
+            }
+        );
+        assert_eq!(drain.active(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_drain_idle() {
+        let drain = Drain::default();
+        assert_eq!(
+            drain.drain(Duration::from_secs(10)).await,
+            DrainReport::default()
+        );
+    }
+}
@@ -16,6 +16,8 @@ pub enum DispatchError {
     Route(#[from] RouteError),
     #[error("[option] {0}")]
     Option(#[from] OptionError),
+    #[error("[io] {0}")]
+    Io(#[from] std::io::Error),
 }
 
 #[derive(Debug, Error)]
@@ -36,12 +38,30 @@ pub enum OutboundError {
     Service(#[from] OutServiceError),
     #[error("<option> {0}")]
     Option(#[from] OptionError),
+    #[error("<group> {0}")]
+    Group(String),
+    #[error("<detour> {0}")]
+    Detour(String),
+    #[error("<state> {0}")]
+    State(String),
+    #[error("<io> {0}")]
+    Io(#[from] std::io::Error),
 }
 
 #[derive(Debug, Error)]
 pub enum RouteError {
     #[error("<option> {0}")]
     Option(#[from] OptionError),
+    #[error("<regex> {0}")]
+    Regex(#[from] regex::Error),
+    #[error("<cidr> {0}")]
+    Cidr(#[from] ipnet::AddrParseError),
+    #[error("<port> invalid range ({0})")]
+    PortRange(String),
+    #[error("<geoip> {0}")]
+    GeoIp(String),
+    #[error("<rule_set> {0}")]
+    RuleSet(String),
 }
 
 #[derive(Debug, Error)]
@@ -54,6 +74,8 @@ pub enum OptionError {
     Serialize(String),
     #[error("deserialize ({0})")]
     Deserialize(String),
+    #[error("invalid ({0})")]
+    Invalid(String),
 }
 
 #[derive(Debug, Error)]
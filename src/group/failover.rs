@@ -0,0 +1,189 @@
+//! Failover Group
+//!
+//! Try members in order until one connects. A member is marked down
+//! after consecutive failures, and tried again once cooldown passed.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::OutboundError;
+
+fn default_max_failures() -> u32 {
+    3
+}
+
+fn default_cooldown() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailoverOption {
+    pub outbounds: Vec<String>,
+    // consecutive failures to mark member down, default 3
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32,
+    // how long a member stays down, default 30s
+    #[serde(default = "default_cooldown")]
+    pub cooldown: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Breaker {
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Failover {
+    tag: String,
+    outbounds: Vec<String>,
+    max_failures: u32,
+    cooldown: Duration,
+
+    breaker: Vec<Mutex<Breaker>>,
+}
+
+impl Failover {
+    pub fn init(tag: String, opt: FailoverOption) -> Result<Self, OutboundError> {
+        if opt.outbounds.is_empty() {
+            return Err(OutboundError::Group(format!("{} has no member", tag)));
+        }
+
+        Ok(Self {
+            tag,
+            breaker: opt.outbounds.iter().map(|_| Mutex::default()).collect(),
+            outbounds: opt.outbounds,
+            max_failures: opt.max_failures.max(1),
+            cooldown: opt.cooldown,
+        })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn outbounds(&self) -> &[String] {
+        &self.outbounds
+    }
+
+    /// Member is down and cooldown not passed yet.
+    pub fn is_down(&self, member: &str) -> bool {
+        let Some(i) = self.position(member) else {
+            return false;
+        };
+
+        matches!(self.breaker[i].lock().unwrap().down_until, Some(t) if t > Instant::now())
+    }
+
+    /// Members to try in order, down members are skipped.
+    /// If all are down, try all of them anyway.
+    pub fn candidates(&self) -> Vec<&str> {
+        let up: Vec<&str> = self
+            .outbounds
+            .iter()
+            .filter(|o| !self.is_down(o))
+            .map(|o| o.as_str())
+            .collect();
+
+        if up.is_empty() {
+            self.outbounds.iter().map(|o| o.as_str()).collect()
+        } else {
+            up
+        }
+    }
+
+    pub fn report(&self, member: &str, ok: bool) {
+        let Some(i) = self.position(member) else {
+            return;
+        };
+        let mut breaker = self.breaker[i].lock().unwrap();
+
+        if ok {
+            if breaker.down_until.is_some() {
+                tracing::info!("[group] failover[{}] [{}] is up", self.tag, member);
+            }
+            *breaker = Breaker::default();
+            return;
+        }
+
+        breaker.failures += 1;
+        // a member tried after cooldown goes down again on first failure
+        if breaker.failures >= self.max_failures || breaker.down_until.is_some() {
+            tracing::warn!(
+                "[group] failover[{}] [{}] is down ({} failures)",
+                self.tag,
+                member,
+                breaker.failures
+            );
+            breaker.down_until = Some(Instant::now() + self.cooldown);
+        }
+    }
+
+    fn position(&self, member: &str) -> Option<usize> {
+        self.outbounds.iter().position(|o| o == member)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failover(cooldown: Duration) -> Failover {
+        Failover::init(
+            "fo".into(),
+            FailoverOption {
+                outbounds: vec!["a".into(), "b".into()],
+                max_failures: 2,
+                cooldown,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_failover_breaker() {
+        let fo = failover(Duration::from_secs(60));
+        assert_eq!(fo.candidates(), vec!["a", "b"]);
+
+        fo.report("a", false);
+        assert_eq!(fo.candidates(), vec!["a", "b"]);
+        fo.report("a", false);
+        assert!(fo.is_down("a"));
+        assert_eq!(fo.candidates(), vec!["b"]);
+
+        // all down, try all
+        fo.report("b", false);
+        fo.report("b", false);
+        assert_eq!(fo.candidates(), vec!["a", "b"]);
+
+        fo.report("b", true);
+        assert_eq!(fo.candidates(), vec!["b"]);
+    }
+
+    #[test]
+    fn test_failover_cooldown() {
+        let fo = failover(Duration::ZERO);
+
+        fo.report("a", false);
+        fo.report("a", false);
+        // cooldown passed, tried again
+        assert!(!fo.is_down("a"));
+        assert_eq!(fo.candidates(), vec!["a", "b"]);
+
+        // one more failure marks it down again
+        let fo = failover(Duration::from_secs(60));
+        fo.report("a", false);
+        fo.report("a", false);
+        fo.breaker[0].lock().unwrap().down_until = Some(Instant::now());
+        assert!(!fo.is_down("a"));
+        fo.report("a", false);
+        assert!(fo.is_down("a"));
+
+        fo.report("a", true);
+        assert!(!fo.is_down("a"));
+    }
+}
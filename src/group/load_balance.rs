@@ -0,0 +1,311 @@
+//! LoadBalance Group
+//!
+//! Spread connections across members by strategy.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use kapibara_service::{Address, ServiceAddress};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{error::OptionError, OutboundError};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    WeightedRandom,
+    LeastConnections,
+    ConsistentHash,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKey {
+    // destination host, port is ignored
+    #[default]
+    Dest,
+    // source ip, falls back to dest if unknown
+    Source,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoadBalanceOption {
+    pub outbounds: Vec<String>,
+    #[serde(default)]
+    pub strategy: Strategy,
+    // used by weighted_random, default 1
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub weight: HashMap<String, u32>,
+    // used by consistent_hash
+    #[serde(default)]
+    pub hash_key: HashKey,
+}
+
+#[derive(Debug)]
+pub struct LoadBalance {
+    tag: String,
+    outbounds: Vec<String>,
+    strategy: Strategy,
+    weight: Vec<u32>,
+    hash_key: HashKey,
+
+    next: AtomicUsize,
+    // active connections of each member
+    active: Vec<Arc<AtomicUsize>>,
+}
+
+impl LoadBalance {
+    pub fn init(tag: String, opt: LoadBalanceOption) -> Result<Self, OutboundError> {
+        if opt.outbounds.is_empty() {
+            return Err(OutboundError::Group(format!("{} has no member", tag)));
+        }
+
+        if let Some(unknown) = opt.weight.keys().find(|k| !opt.outbounds.contains(k)) {
+            return Err(OptionError::UnknownTag(unknown.to_owned()).into());
+        }
+
+        let weight: Vec<u32> = opt
+            .outbounds
+            .iter()
+            .map(|o| opt.weight.get(o).copied().unwrap_or(1))
+            .collect();
+        if opt.strategy == Strategy::WeightedRandom && weight.iter().all(|w| *w == 0) {
+            return Err(OutboundError::Group(format!("{} all weight is zero", tag)));
+        }
+
+        Ok(Self {
+            tag,
+            active: opt.outbounds.iter().map(|_| Arc::default()).collect(),
+            outbounds: opt.outbounds,
+            strategy: opt.strategy,
+            weight,
+            hash_key: opt.hash_key,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn outbounds(&self) -> &[String] {
+        &self.outbounds
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    /// Active connections of member.
+    pub fn active(&self, member: &str) -> usize {
+        match self.outbounds.iter().position(|o| o == member) {
+            Some(i) => self.active[i].load(Ordering::Relaxed),
+            None => 0,
+        }
+    }
+
+    /// Pick a member, the guard counts an active connection until dropped.
+    pub fn pick(&self, dest: &ServiceAddress, source: Option<SocketAddr>) -> (&str, ConnGuard) {
+        let i = match self.strategy {
+            Strategy::RoundRobin => {
+                self.next.fetch_add(1, Ordering::Relaxed) % self.outbounds.len()
+            }
+            Strategy::WeightedRandom => self.weighted_random(),
+            Strategy::LeastConnections => self.least_connections(),
+            Strategy::ConsistentHash => self.consistent_hash(dest, source),
+        };
+
+        (&self.outbounds[i], ConnGuard::new(self.active[i].clone()))
+    }
+
+    fn weighted_random(&self) -> usize {
+        let total: u64 = self.weight.iter().map(|w| *w as u64).sum();
+        let mut n = fastrand::u64(0..total);
+
+        for (i, w) in self.weight.iter().enumerate() {
+            if n < *w as u64 {
+                return i;
+            }
+            n -= *w as u64;
+        }
+
+        0
+    }
+
+    fn least_connections(&self) -> usize {
+        // start from rotating offset, so ties are spread
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.outbounds.len())
+            .map(|i| (i + offset) % self.outbounds.len())
+            .min_by_key(|i| self.active[*i].load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    // rendezvous hashing, only keys of a removed member move
+    fn consistent_hash(&self, dest: &ServiceAddress, source: Option<SocketAddr>) -> usize {
+        let key = match (self.hash_key, source) {
+            (HashKey::Source, Some(s)) => s.ip().to_string(),
+            _ => match dest.addr {
+                Address::Domain(ref d) => d.to_owned(),
+                Address::Socket(ref ip) => ip.to_string(),
+            },
+        };
+
+        self.outbounds
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, member)| {
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                member.hash(&mut hasher);
+                hasher.finish()
+            })
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnGuard(Arc<AtomicUsize>);
+
+impl ConnGuard {
+    fn new(active: Arc<AtomicUsize>) -> Self {
+        active.fetch_add(1, Ordering::Relaxed);
+        Self(active)
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Stream holding a guard, released when the stream is dropped.
+pub struct Guarded<S> {
+    inner: S,
+    _guard: ConnGuard,
+}
+
+impl<S> Guarded<S> {
+    pub fn new(inner: S, guard: ConnGuard) -> Self {
+        Self {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Guarded<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Guarded<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_balance(strategy: Strategy) -> LoadBalance {
+        LoadBalance::init(
+            "lb".into(),
+            LoadBalanceOption {
+                outbounds: vec!["a".into(), "b".into(), "c".into()],
+                strategy,
+                weight: HashMap::from([("b".into(), 0), ("c".into(), 3)]),
+                hash_key: HashKey::Dest,
+            },
+        )
+        .unwrap()
+    }
+
+    fn dest(domain: &str) -> ServiceAddress {
+        ServiceAddress::new(Address::Domain(domain.into()), 443)
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let lb = load_balance(Strategy::RoundRobin);
+        let picked: Vec<_> = (0..4)
+            .map(|_| lb.pick(&dest("example.com"), None).0.to_owned())
+            .collect();
+        assert_eq!(picked, vec!["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn test_weighted_random() {
+        let lb = load_balance(Strategy::WeightedRandom);
+        for _ in 0..100 {
+            assert_ne!(lb.pick(&dest("example.com"), None).0, "b");
+        }
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let lb = load_balance(Strategy::LeastConnections);
+
+        let (a, guard_a) = lb.pick(&dest("example.com"), None);
+        let (b, _guard_b) = lb.pick(&dest("example.com"), None);
+        let (c, _guard_c) = lb.pick(&dest("example.com"), None);
+        let mut picked = vec![a, b, c];
+        picked.sort();
+        assert_eq!(picked, vec!["a", "b", "c"]);
+
+        let a = a.to_owned();
+        drop(guard_a);
+        assert_eq!(lb.active(&a), 0);
+        assert_eq!(lb.pick(&dest("example.com"), None).0, a);
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let lb = load_balance(Strategy::ConsistentHash);
+        let first = lb.pick(&dest("example.com"), None).0.to_owned();
+        for _ in 0..10 {
+            assert_eq!(lb.pick(&dest("example.com"), None).0, first);
+        }
+
+        let mut lb = lb;
+        lb.hash_key = HashKey::Source;
+        let source: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let picked = lb.pick(&dest("example.com"), Some(source)).0.to_owned();
+        // port is ignored
+        let source: SocketAddr = "10.0.0.1:5678".parse().unwrap();
+        assert_eq!(lb.pick(&dest("example.org"), Some(source)).0, picked);
+    }
+}
@@ -0,0 +1,102 @@
+//! Kapibara Outbound Group
+//!
+//! Group wraps other outbounds (or groups) by tag and
+//! decides which of them a connection goes through.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{state::State, OutboundError};
+
+pub mod selector;
+pub use selector::{Selector, SelectorOption};
+
+pub mod failover;
+pub use failover::{Failover, FailoverOption};
+
+pub mod load_balance;
+pub use load_balance::{LoadBalance, LoadBalanceOption};
+
+pub mod race;
+pub use race::{Race, RaceOption};
+
+pub mod url_test;
+pub use url_test::{UrlTest, UrlTestOption};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupOption {
+    pub tag: String,
+    pub opt: GroupKindOption,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GroupKindOption {
+    Selector(SelectorOption),
+    UrlTest(UrlTestOption),
+    LoadBalance(LoadBalanceOption),
+    Failover(FailoverOption),
+    Race(RaceOption),
+}
+
+pub enum Group {
+    Selector(Selector),
+    UrlTest(UrlTest),
+    LoadBalance(LoadBalance),
+    Failover(Failover),
+    Race(Race),
+}
+
+impl Group {
+    pub fn init(opt: GroupOption, state: Arc<State>) -> Result<Self, OutboundError> {
+        let group = match opt.opt {
+            GroupKindOption::Selector(o) => Self::Selector(Selector::init(opt.tag, o, state)?),
+            GroupKindOption::UrlTest(o) => Self::UrlTest(UrlTest::init(opt.tag, o)?),
+            GroupKindOption::LoadBalance(o) => Self::LoadBalance(LoadBalance::init(opt.tag, o)?),
+            GroupKindOption::Failover(o) => Self::Failover(Failover::init(opt.tag, o)?),
+            GroupKindOption::Race(o) => Self::Race(Race::init(opt.tag, o)?),
+        };
+
+        Ok(group)
+    }
+
+    pub fn tag(&self) -> &str {
+        match self {
+            Self::Selector(g) => g.tag(),
+            Self::UrlTest(g) => g.tag(),
+            Self::LoadBalance(g) => g.tag(),
+            Self::Failover(g) => g.tag(),
+            Self::Race(g) => g.tag(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Selector(_) => "selector",
+            Self::UrlTest(_) => "url_test",
+            Self::LoadBalance(_) => "load_balance",
+            Self::Failover(_) => "failover",
+            Self::Race(_) => "race",
+        }
+    }
+
+    /// Current member of selector and url_test.
+    pub fn selected(&self) -> Option<String> {
+        match self {
+            Self::Selector(g) => Some(g.selected()),
+            Self::UrlTest(g) => Some(g.selected()),
+            _ => None,
+        }
+    }
+
+    pub fn members(&self) -> &[String] {
+        match self {
+            Self::Selector(g) => g.outbounds(),
+            Self::UrlTest(g) => g.outbounds(),
+            Self::LoadBalance(g) => g.outbounds(),
+            Self::Failover(g) => g.outbounds(),
+            Self::Race(g) => g.outbounds(),
+        }
+    }
+}
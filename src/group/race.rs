@@ -0,0 +1,64 @@
+//! Race Group
+//!
+//! Connect and handshake through all members at the same time,
+//! keep the first one finished and cancel the rest.
+
+use serde::{Deserialize, Serialize};
+
+use crate::OutboundError;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RaceOption {
+    pub outbounds: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Race {
+    tag: String,
+    outbounds: Vec<String>,
+}
+
+impl Race {
+    pub fn init(tag: String, opt: RaceOption) -> Result<Self, OutboundError> {
+        if opt.outbounds.len() < 2 {
+            return Err(OutboundError::Group(format!(
+                "{} needs at least 2 members",
+                tag
+            )));
+        }
+
+        Ok(Self {
+            tag,
+            outbounds: opt.outbounds,
+        })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn outbounds(&self) -> &[String] {
+        &self.outbounds
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_race_init() {
+        let opt = RaceOption {
+            outbounds: vec!["a".into()],
+        };
+        assert!(Race::init("race".into(), opt).is_err());
+
+        let opt = RaceOption {
+            outbounds: vec!["a".into(), "b".into()],
+        };
+        assert_eq!(
+            Race::init("race".into(), opt).unwrap().outbounds(),
+            ["a", "b"]
+        );
+    }
+}
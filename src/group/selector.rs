@@ -0,0 +1,138 @@
+//! Selector Group
+//!
+//! Forward to the member selected at runtime.
+
+use std::sync::{Arc, RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::OptionError, state::State, OutboundError};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SelectorOption {
+    pub outbounds: Vec<String>,
+    // default is the first member
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Selector {
+    tag: String,
+    outbounds: Vec<String>,
+    selected: RwLock<String>,
+    state: Arc<State>,
+}
+
+impl Selector {
+    pub fn init(
+        tag: String,
+        opt: SelectorOption,
+        state: Arc<State>,
+    ) -> Result<Self, OutboundError> {
+        let first = opt
+            .outbounds
+            .first()
+            .cloned()
+            .ok_or(OutboundError::Group(format!("{} has no member", tag)))?;
+
+        if let Some(ref default) = opt.default {
+            if !opt.outbounds.contains(default) {
+                return Err(OptionError::UnknownTag(default.to_owned()).into());
+            }
+        }
+
+        // saved selection wins over default
+        let selected = state
+            .selected(&tag)
+            .filter(|s| opt.outbounds.contains(s))
+            .or(opt.default)
+            .unwrap_or(first);
+
+        Ok(Self {
+            tag,
+            outbounds: opt.outbounds,
+            selected: RwLock::new(selected),
+            state,
+        })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn outbounds(&self) -> &[String] {
+        &self.outbounds
+    }
+
+    pub fn selected(&self) -> String {
+        self.selected.read().unwrap().clone()
+    }
+
+    /// Switch to member and save it to state file.
+    pub fn select(&self, member: &str) -> Result<(), OutboundError> {
+        if !self.outbounds.iter().any(|o| o == member) {
+            return Err(OptionError::UnknownTag(member.to_owned()).into());
+        }
+
+        *self.selected.write().unwrap() = member.to_owned();
+        tracing::info!("[group] {} select [{}]", self.tag, member);
+
+        self.state.set_selected(&self.tag, member)
+    }
+
+    /// Switch to member without saving, ignored if not a member.
+    pub fn restore(&self, member: &str) {
+        if self.outbounds.iter().any(|o| o == member) {
+            *self.selected.write().unwrap() = member.to_owned();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn option() -> SelectorOption {
+        SelectorOption {
+            outbounds: vec!["a".into(), "b".into()],
+            default: None,
+        }
+    }
+
+    #[test]
+    fn test_selector_state() {
+        let path = std::env::temp_dir().join(format!("kapibara-state-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let load = || Arc::new(State::load(Some(path.to_string_lossy().into())));
+
+        let selector = Selector::init("proxy".into(), option(), load()).unwrap();
+        assert_eq!(selector.selected(), "a");
+        assert!(selector.select("c").is_err());
+        selector.select("b").unwrap();
+        assert_eq!(selector.selected(), "b");
+
+        // restored after restart
+        let selector = Selector::init("proxy".into(), option(), load()).unwrap();
+        assert_eq!(selector.selected(), "b");
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_selector_default() {
+        let state = Arc::new(State::default());
+        let mut opt = option();
+        opt.default = Some("b".into());
+        let selector = Selector::init("proxy".into(), opt, state.clone()).unwrap();
+        assert_eq!(selector.selected(), "b");
+
+        let mut opt = option();
+        opt.default = Some("c".into());
+        assert!(Selector::init("proxy".into(), opt, state.clone()).is_err());
+
+        let mut opt = option();
+        opt.outbounds.clear();
+        assert!(Selector::init("proxy".into(), opt, state).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! UrlTest Group
+//!
+//! Probe every member by dialing the test destination,
+//! forward to the fastest healthy one.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+
+use kapibara_service::{Address, OutboundPacket, PacketType, ServiceAddress};
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::OutboundError;
+
+fn default_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_tolerance() -> Duration {
+    Duration::from_millis(50)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UrlTestOption {
+    pub outbounds: Vec<String>,
+    // test destination, host:port
+    pub dest: String,
+    // sent after handshake, then wait for the first response byte
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    // default 300s
+    #[serde(default = "default_interval")]
+    pub interval: Duration,
+    // probe timeout, default 5s
+    #[serde(default = "default_timeout")]
+    pub timeout: Duration,
+    // only switch if the fastest is faster than this, default 50ms
+    #[serde(default = "default_tolerance")]
+    pub tolerance: Duration,
+}
+
+#[derive(Debug)]
+pub struct UrlTest {
+    tag: String,
+    outbounds: Vec<String>,
+    dest: ServiceAddress,
+    payload: Option<Vec<u8>>,
+    interval: Duration,
+    timeout: Duration,
+    tolerance: Duration,
+
+    // none if the last probe failed
+    latency: RwLock<HashMap<String, Option<Duration>>>,
+    selected: RwLock<String>,
+}
+
+impl UrlTest {
+    pub fn init(tag: String, opt: UrlTestOption) -> Result<Self, OutboundError> {
+        let first = opt
+            .outbounds
+            .first()
+            .cloned()
+            .ok_or(OutboundError::Group(format!("{} has no member", tag)))?;
+
+        let dest = parse_dest(&opt.dest)
+            .ok_or(OutboundError::Group(format!("invalid dest ({})", opt.dest)))?;
+
+        Ok(Self {
+            tag,
+            outbounds: opt.outbounds,
+            dest,
+            payload: opt.payload.map(String::into_bytes),
+            interval: opt.interval,
+            timeout: opt.timeout,
+            tolerance: opt.tolerance,
+            latency: RwLock::new(HashMap::new()),
+            selected: RwLock::new(first),
+        })
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn outbounds(&self) -> &[String] {
+        &self.outbounds
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn selected(&self) -> String {
+        self.selected.read().unwrap().clone()
+    }
+
+    /// Latency of last probe, none if failed or not probed yet.
+    pub fn latency(&self, member: &str) -> Option<Duration> {
+        self.latency.read().unwrap().get(member).copied().flatten()
+    }
+
+    pub fn probe_packet(&self) -> OutboundPacket {
+        OutboundPacket {
+            typ: PacketType::Tcp,
+            dest: self.dest.clone(),
+        }
+    }
+
+    /// Send payload and wait for the first response byte.
+    pub async fn measure<S>(&self, stream: &mut S) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(ref payload) = self.payload else {
+            return Ok(());
+        };
+
+        stream.write_all(payload).await?;
+        stream.flush().await?;
+
+        let mut buf = [0u8; 1];
+        if stream.read(&mut buf).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(())
+    }
+
+    /// Record probe results and switch to the fastest member,
+    /// unless the current one is healthy and within tolerance.
+    pub fn update(&self, results: Vec<(String, Option<Duration>)>) {
+        let fastest = results
+            .iter()
+            .filter_map(|(m, l)| l.map(|l| (m, l)))
+            .min_by_key(|(_, l)| *l)
+            .map(|(m, l)| (m.to_owned(), l));
+
+        let mut latency = self.latency.write().unwrap();
+        latency.extend(results);
+
+        let Some((fastest, best)) = fastest else {
+            tracing::warn!("[group] url_test[{}] no healthy member", self.tag);
+            return;
+        };
+
+        let mut selected = self.selected.write().unwrap();
+        let keep = match latency.get(&*selected).copied().flatten() {
+            Some(current) => current <= best + self.tolerance,
+            None => false,
+        };
+
+        if !keep && *selected != fastest {
+            tracing::info!(
+                "[group] url_test[{}] switch [{}] -> [{}] ({}ms)",
+                self.tag,
+                selected,
+                fastest,
+                best.as_millis()
+            );
+            *selected = fastest;
+        }
+    }
+}
+
+/// Time a probe, none on error or timeout.
+pub async fn timed<F, E>(timeout: Duration, probe: F) -> Option<Duration>
+where
+    F: std::future::Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    let start = Instant::now();
+    match tokio::time::timeout(timeout, probe).await {
+        Ok(Ok(())) => Some(start.elapsed()),
+        Ok(Err(e)) => {
+            tracing::debug!("[group] <probe> {}", e);
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+fn parse_dest(dest: &str) -> Option<ServiceAddress> {
+    let (host, port) = dest.rsplit_once(':')?;
+    let port = port.parse::<u16>().ok()?;
+
+    // [::1]:80
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+
+    let addr = match host.parse::<IpAddr>() {
+        Ok(ip) => Address::Socket(ip),
+        Err(_) => Address::Domain(host.to_owned()),
+    };
+
+    Some(ServiceAddress::new(addr, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn url_test(payload: Option<&str>) -> UrlTest {
+        UrlTest::init(
+            "auto".into(),
+            UrlTestOption {
+                outbounds: vec!["a".into(), "b".into(), "c".into()],
+                dest: "www.example.com:80".into(),
+                payload: payload.map(|p| p.into()),
+                interval: default_interval(),
+                timeout: Duration::from_secs(1),
+                tolerance: default_tolerance(),
+            },
+        )
+        .unwrap()
+    }
+
+    fn ms(n: u64) -> Option<Duration> {
+        Some(Duration::from_millis(n))
+    }
+
+    #[test]
+    fn test_parse_dest() {
+        assert_eq!(
+            parse_dest("www.example.com:80"),
+            Some(ServiceAddress::new(
+                Address::Domain("www.example.com".into()),
+                80
+            ))
+        );
+        assert_eq!(
+            parse_dest("[::1]:443"),
+            Some(ServiceAddress::new(
+                Address::Socket("::1".parse().unwrap()),
+                443
+            ))
+        );
+        assert_eq!(parse_dest("example.com"), None);
+        assert_eq!(parse_dest(":80"), None);
+    }
+
+    #[test]
+    fn test_url_test_update() {
+        let group = url_test(None);
+        assert_eq!(group.selected(), "a");
+
+        group.update(vec![
+            ("a".into(), ms(200)),
+            ("b".into(), ms(100)),
+            ("c".into(), None),
+        ]);
+        assert_eq!(group.selected(), "b");
+
+        // within tolerance, keep current
+        group.update(vec![
+            ("a".into(), ms(80)),
+            ("b".into(), ms(110)),
+            ("c".into(), ms(90)),
+        ]);
+        assert_eq!(group.selected(), "b");
+
+        // current unhealthy
+        group.update(vec![
+            ("a".into(), ms(80)),
+            ("b".into(), None),
+            ("c".into(), ms(90)),
+        ]);
+        assert_eq!(group.selected(), "a");
+        assert_eq!(group.latency("b"), None);
+
+        // nothing healthy, keep current
+        group.update(vec![
+            ("a".into(), None),
+            ("b".into(), None),
+            ("c".into(), None),
+        ]);
+        assert_eq!(group.selected(), "a");
+    }
+
+    #[tokio::test]
+    async fn test_url_test_measure() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // echo server
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = s.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        let group = url_test(Some("ping"));
+        let latency = timed(group.timeout(), async {
+            let mut stream = TcpStream::connect(addr).await?;
+            group.measure(&mut stream).await
+        })
+        .await;
+        assert!(latency.is_some());
+
+        // server never answers
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = silent.local_addr().unwrap();
+        let latency = timed(Duration::from_millis(50), async {
+            let mut stream = TcpStream::connect(addr).await?;
+            group.measure(&mut stream).await
+        })
+        .await;
+        assert!(latency.is_none());
+    }
+}
@@ -0,0 +1,262 @@
+//! Kapibara Http
+//!
+//! Minimal http/1 server for local control endpoints.
+
+use std::{convert::Infallible, future::Future, sync::Arc};
+
+use futures_util::{SinkExt, Stream, StreamExt};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, Empty, Full, Limited, StreamBody};
+use hyper::{
+    body::{Bytes, Frame, Incoming},
+    header::{self, HeaderValue},
+    server::conn::http1,
+    service::service_fn,
+    upgrade, Request, Response, StatusCode, Uri,
+};
+use hyper_util::rt::TokioIo;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::net::TcpListener;
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+
+pub type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+// largest request body accepted
+const MAX_BODY: usize = 64 * 1024;
+
+/// Accept connections and answer each request with handler, never return.
+pub async fn serve<H, F>(listener: TcpListener, handler: H)
+where
+    H: Fn(Request<Incoming>) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Body>> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                tracing::debug!("[http] <accept> {}", e);
+                continue;
+            }
+        };
+
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let res = handler(req);
+                async move { Ok::<_, Infallible>(res.await) }
+            });
+
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+            {
+                tracing::debug!("[http] {}", e);
+            }
+        });
+    }
+}
+
+pub fn full<T: Into<Bytes>>(data: T) -> Body {
+    Full::new(data.into()).boxed_unsync()
+}
+
+pub fn empty(status: StatusCode) -> Response<Body> {
+    let mut res = Response::new(Empty::new().boxed_unsync());
+    *res.status_mut() = status;
+    res
+}
+
+pub fn text(status: StatusCode, content_type: &str, body: String) -> Response<Body> {
+    let mut res = Response::new(full(body));
+    *res.status_mut() = status;
+    if let Ok(v) = content_type.parse() {
+        res.headers_mut().insert(header::CONTENT_TYPE, v);
+    }
+    res
+}
+
+pub fn json<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    match serde_json::to_string(value) {
+        Ok(body) => text(status, "application/json", body),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, &e.to_string()),
+    }
+}
+
+pub fn error(status: StatusCode, message: &str) -> Response<Body> {
+    json(status, &serde_json::json!({ "message": message }))
+}
+
+pub fn not_found() -> Response<Body> {
+    error(StatusCode::NOT_FOUND, "not found")
+}
+
+/// Allow requests from any origin, for web dashboards.
+pub fn cors(mut res: Response<Body>) -> Response<Body> {
+    let headers = res.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_ORIGIN,
+        HeaderValue::from_static("*"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, POST, PUT, PATCH, DELETE"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Content-Type, Authorization"),
+    );
+    res
+}
+
+/// Send items as json messages over websocket if asked,
+/// otherwise as json lines in a chunked body.
+pub fn stream<S, T>(req: &mut Request<Incoming>, items: S) -> Response<Body>
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Serialize,
+{
+    let items = items.map(|item| serde_json::to_string(&item).unwrap_or_default());
+
+    let Some(key) = websocket_key(req) else {
+        let body = items.map(|line| Ok(Frame::data(Bytes::from(line + "\n"))));
+        let mut res = Response::new(StreamBody::new(body).boxed_unsync());
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/json"),
+        );
+        return res;
+    };
+
+    let on_upgrade = upgrade::on(req);
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(u) => u,
+            Err(e) => {
+                tracing::debug!("[http] <upgrade> {}", e);
+                return;
+            }
+        };
+
+        let ws = WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None).await;
+        let (mut tx, mut rx) = ws.split();
+        let mut items = std::pin::pin!(items);
+
+        loop {
+            tokio::select! {
+                item = items.next() => {
+                    let Some(item) = item else { break };
+                    if tx.send(Message::Text(item)).await.is_err() {
+                        break;
+                    }
+                }
+                msg = rx.next() => match msg {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    _ => {}
+                }
+            }
+        }
+
+        let _ = tx.close().await;
+    });
+
+    let mut res = empty(StatusCode::SWITCHING_PROTOCOLS);
+    let headers = res.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+    if let Ok(v) = derive_accept_key(key.as_bytes()).parse() {
+        headers.insert(header::SEC_WEBSOCKET_ACCEPT, v);
+    }
+    res
+}
+
+fn websocket_key(req: &Request<Incoming>) -> Option<String> {
+    let headers = req.headers();
+    let upgrade = headers.get(header::UPGRADE)?.to_str().ok()?;
+    if !upgrade.eq_ignore_ascii_case("websocket") {
+        return None;
+    }
+
+    headers
+        .get(header::SEC_WEBSOCKET_KEY)?
+        .to_str()
+        .ok()
+        .map(|k| k.to_owned())
+}
+
+/// Read request body as json.
+pub async fn read_json<T: DeserializeOwned>(body: Incoming) -> Result<T, Response<Body>> {
+    let data = match Limited::new(body, MAX_BODY).collect().await {
+        Ok(b) => b.to_bytes(),
+        Err(e) => return Err(error(StatusCode::BAD_REQUEST, &e.to_string())),
+    };
+
+    serde_json::from_slice(&data).map_err(|e| error(StatusCode::BAD_REQUEST, &e.to_string()))
+}
+
+/// Percent decoded value of query parameter.
+pub fn query(uri: &Uri, key: &str) -> Option<String> {
+    uri.query()?
+        .split('&')
+        .filter_map(|kv| kv.split_once('=').or(Some((kv, ""))))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| decode(v, true))
+}
+
+/// Percent decoded segments of path.
+pub fn path(uri: &Uri) -> Vec<String> {
+    uri.path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| decode(s, false))
+        .collect()
+}
+
+// plus is space only in query
+fn decode(value: &str, plus: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' if plus => out.push(b' '),
+            b'%' if i + 2 < bytes.len()
+                && bytes[i + 1].is_ascii_hexdigit()
+                && bytes[i + 2].is_ascii_hexdigit() =>
+            {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                out.push(u8::from_str_radix(hex, 16).unwrap_or_default());
+                i += 2;
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let uri: Uri = "/connections?user=a%40b+c&id=1&empty".parse().unwrap();
+        assert_eq!(query(&uri, "user").as_deref(), Some("a@b c"));
+        assert_eq!(query(&uri, "id").as_deref(), Some("1"));
+        assert_eq!(query(&uri, "empty").as_deref(), Some(""));
+        assert_eq!(query(&uri, "none"), None);
+
+        let uri: Uri = "/?a=%4".parse().unwrap();
+        assert_eq!(query(&uri, "a").as_deref(), Some("%4"));
+
+        let uri: Uri = "/proxies/a+b%20c/".parse().unwrap();
+        assert_eq!(path(&uri), vec!["proxies", "a+b c"]);
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::sync::Arc;
 
-use crate::InboundError;
+use crate::{InboundError, SniffOption};
 use kapibara_service::{InboundService, InboundServiceOption};
 use kapibara_transport::{TransportServer, TransportServerOption};
 use serde::{Deserialize, Serialize};
@@ -12,12 +12,16 @@ pub struct InboundOption {
     pub tag: String,
     pub server: TransportServerOption,
     pub service: InboundServiceOption,
+    // sniff domain of ip destination from the first client bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sniff: Option<SniffOption>,
 }
 
 pub struct Inbound {
     tag: String,
     svc: Arc<InboundService>,
     srv: Arc<TransportServer>,
+    sniff: Option<SniffOption>,
 }
 
 impl Inbound {
@@ -29,6 +33,7 @@ impl Inbound {
             tag: in_opt.tag,
             svc: Arc::new(svc),
             srv: Arc::new(srv),
+            sniff: in_opt.sniff,
         })
     }
 
@@ -47,4 +52,8 @@ impl Inbound {
     pub fn get_server(&self) -> Arc<TransportServer> {
         self.srv.clone()
     }
+
+    pub fn sniff(&self) -> Option<&SniffOption> {
+        self.sniff.as_ref()
+    }
 }
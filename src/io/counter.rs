@@ -0,0 +1,95 @@
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Stream that counts bytes read and written while in use.
+pub struct Counter<S> {
+    inner: S,
+    read: Arc<AtomicU64>,
+    write: Arc<AtomicU64>,
+}
+
+impl<S> Counter<S> {
+    pub fn new(inner: S, read: Arc<AtomicU64>, write: Arc<AtomicU64>) -> Self {
+        Self { inner, read, write }
+    }
+
+    pub fn inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counter<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        let before = buf.filled().len();
+        let res = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let n = buf.filled().len() - before;
+            this.read.fetch_add(n as u64, Ordering::Relaxed);
+        }
+
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counter<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let res = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.write.fetch_add(n as u64, Ordering::Relaxed);
+        }
+
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_counter() {
+        let (a, mut b) = duplex(64);
+        let (read, write) = (Arc::default(), Arc::default());
+        let mut a = Counter::new(a, Arc::clone(&read), Arc::clone(&write));
+
+        a.write_all(b"hello").await.unwrap();
+        b.write_all(b"hi").await.unwrap();
+
+        let mut buf = [0u8; 2];
+        a.read_exact(&mut buf).await.unwrap();
+
+        assert_eq!(read.load(Ordering::Relaxed), 2);
+        assert_eq!(write.load(Ordering::Relaxed), 5);
+    }
+}
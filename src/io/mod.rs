@@ -10,9 +10,17 @@ pub use timer::StreamTimer;
 pub mod copy;
 pub use copy::{copy, copy_bi, copy_bi_with_size, copy_with_size, Copy};
 
+pub mod rewind;
+pub use rewind::Rewind;
+
+pub mod counter;
+pub use counter::Counter;
+
 pub trait StreamTrait: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
 impl<S: AsyncRead + AsyncWrite + Send + Sync + Unpin> StreamTrait for S {}
 
+pub type BoxStream = Box<dyn StreamTrait>;
+
 pub trait ToStreamTimer: StreamTrait
 where
     Self: Sized,
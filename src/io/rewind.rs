@@ -0,0 +1,98 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Stream that replays the bytes already read from inner stream.
+pub struct Rewind<S> {
+    pre: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(inner: S) -> Self {
+        Self::new_buffered(inner, Vec::new())
+    }
+
+    pub fn new_buffered(inner: S, pre: Vec<u8>) -> Self {
+        Self { pre, pos: 0, inner }
+    }
+
+    /// Bytes that will be read again before the inner stream.
+    pub fn rewind(&mut self, pre: Vec<u8>) {
+        self.pre = pre;
+        self.pos = 0;
+    }
+
+    pub fn inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.pos < this.pre.len() {
+            let n = buf.remaining().min(this.pre.len() - this.pos);
+            buf.put_slice(&this.pre[this.pos..this.pos + n]);
+            this.pos += n;
+
+            if this.pos == this.pre.len() {
+                this.pre = Vec::new();
+                this.pos = 0;
+            }
+
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_rewind() {
+        let (mut tx, rx) = duplex(64);
+        let mut stream = Rewind::new_buffered(rx, b"hello ".to_vec());
+
+        tx.write_all(b"world").await.unwrap();
+        drop(tx);
+
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+
+        assert_eq!(buf, "hello world");
+    }
+}
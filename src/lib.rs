@@ -8,11 +8,55 @@ pub use inbound::{Inbound, InboundOption};
 pub mod outbound;
 pub use outbound::{Outbound, OutboundOption};
 
+pub mod reject;
+pub use reject::{Reject, RejectOption};
+
+pub mod group;
+pub use group::{
+    FailoverOption, GroupOption, LoadBalanceOption, RaceOption, SelectorOption, UrlTestOption,
+};
+
+pub mod manager;
+pub use manager::OutboundManager;
+
+pub mod state;
+
+pub mod drain;
+pub use drain::DrainReport;
+
+pub mod stats;
+pub use stats::{StatsSnapshot, TrafficSnapshot};
+
+pub mod tracker;
+pub use tracker::{ConnInfo, Tracker};
+
+pub mod access;
+pub use access::AccessLogOption;
+
+pub mod http;
+
+pub mod metrics;
+pub use metrics::MetricsOption;
+
+pub mod logs;
+pub use logs::LogOption;
+
+pub mod api;
+pub use api::ApiOption;
+
+pub mod clash;
+pub use clash::ClashApiOption;
+
 pub mod dispatch;
-pub use dispatch::{Dispatch, DispatchOption};
+pub use dispatch::{Dispatch, DispatchOption, ReloadReport};
 
 pub mod route;
-pub use route::{Route, RouteOption, RouteRule, RouteRuleOption};
+pub use route::{
+    DomainStrategy, Route, RouteContext, RouteDecision, RouteOption, RouteRule, RouteRuleOption,
+};
+
+pub mod sniff;
+pub use sniff::SniffOption;
 
 pub mod dns;
 pub use dns::DnsOption;
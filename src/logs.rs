@@ -0,0 +1,111 @@
+//! Kapibara Logs
+//!
+//! Log option of the binary, and a layer that sends events
+//! to local api subscribers.
+
+use std::{fmt::Write as _, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+// records kept for slow subscribers
+const CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Pretty,
+    #[default]
+    Compact,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LogOption {
+    #[serde(default)]
+    pub format: LogFormat,
+    // per module filter, e.g. "info,kapibara::dispatch=debug",
+    // RUST_LOG takes precedence, default is the level of command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    // log span close with busy and idle time
+    #[serde(default)]
+    pub span_timing: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogEntry {
+    pub level: Level,
+    pub message: String,
+}
+
+fn sender() -> &'static broadcast::Sender<LogEntry> {
+    static SENDER: OnceLock<broadcast::Sender<LogEntry>> = OnceLock::new();
+    SENDER.get_or_init(|| broadcast::channel(CAPACITY).0)
+}
+
+/// Receive events logged from now on.
+pub fn subscribe() -> broadcast::Receiver<LogEntry> {
+    sender().subscribe()
+}
+
+/// Send events to subscribers if any.
+pub struct BroadcastLayer;
+
+impl<S: Subscriber> Layer<S> for BroadcastLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let sender = sender();
+        if sender.receiver_count() == 0 {
+            return;
+        }
+
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+
+        let _ = sender.send(LogEntry {
+            level: *event.metadata().level(),
+            message: visitor.0,
+        });
+    }
+}
+
+// message first, then other fields as key=value
+#[derive(Default)]
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let rest = std::mem::take(&mut self.0);
+            let _ = write!(self.0, "{:?}{}", value, rest);
+        } else {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_broadcast() {
+        let mut rx = subscribe();
+        let subscriber = tracing_subscriber::registry().with(BroadcastLayer);
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!(id = 1, "[dispatch] {}", "closed");
+        });
+
+        let entry = rx.try_recv().unwrap();
+        assert_eq!(entry.level, Level::WARN);
+        assert_eq!(entry.message, "[dispatch] closed id=1");
+    }
+}
@@ -0,0 +1,612 @@
+//! Kapibara Outbound Manager
+//!
+//! Outbound, reject and group share one tag namespace.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use futures_util::future::{join_all, select_ok, BoxFuture};
+use kapibara_service::OutboundPacket;
+use kapibara_transport::Resolver;
+use tokio::task::JoinHandle;
+
+use crate::{
+    error::OptionError,
+    group::{load_balance::Guarded, url_test, Group, GroupOption},
+    io::BoxStream,
+    state::State,
+    Outbound, OutboundError, OutboundOption, Reject, RejectOption,
+};
+
+pub enum Dialed {
+    // tag of the outbound actually connected
+    Stream { tag: String, stream: BoxStream },
+    Reject(Reject),
+}
+
+pub struct OutboundManager {
+    outbound: HashMap<String, Outbound>,
+    reject: HashMap<String, Reject>,
+    // shared with the next manager on reload if option not changed
+    group: HashMap<String, Arc<Group>>,
+    group_opt: HashMap<String, serde_json::Value>,
+}
+
+impl OutboundManager {
+    pub fn init(
+        out_opts: Vec<OutboundOption>,
+        rej_opts: Vec<RejectOption>,
+        group_opts: Vec<GroupOption>,
+        resolver: &Resolver,
+        state: Arc<State>,
+    ) -> Result<Self, OutboundError> {
+        let mut manager = Self {
+            outbound: HashMap::new(),
+            reject: HashMap::new(),
+            group: HashMap::new(),
+            group_opt: HashMap::new(),
+        };
+
+        for out_opt in out_opts {
+            let o = Outbound::init(out_opt, resolver)?;
+            manager.check_tag(o.tag())?;
+            manager.outbound.insert(o.get_tag(), o);
+        }
+
+        for rej_opt in rej_opts {
+            let r = Reject::init(rej_opt);
+            manager.check_tag(r.tag())?;
+            manager.reject.insert(r.get_tag(), r);
+        }
+
+        for group_opt in group_opts {
+            let opt = serde_json::to_value(&group_opt).unwrap_or_default();
+            let g = Group::init(group_opt, state.clone())?;
+            manager.check_tag(g.tag())?;
+            manager.group_opt.insert(g.tag().to_owned(), opt);
+            manager.group.insert(g.tag().to_owned(), Arc::new(g));
+        }
+
+        for tag in manager.group.keys().chain(manager.outbound.keys()) {
+            manager.check_member(tag, &mut vec![])?;
+        }
+
+        Ok(manager)
+    }
+
+    /// Keep runtime state of the manager replaced on reload. Selectors keep
+    /// the selected member if still a member, other groups with unchanged
+    /// option are shared, e.g. probe results, breakers and active counts.
+    pub fn inherit(&mut self, old: &OutboundManager) {
+        for (tag, group) in self.group.iter_mut() {
+            let Some(old_group) = old.group.get(tag) else {
+                continue;
+            };
+
+            if let (Group::Selector(s), Group::Selector(old_s)) = (&**group, &**old_group) {
+                s.restore(&old_s.selected());
+                continue;
+            }
+
+            if self.group_opt.get(tag) == old.group_opt.get(tag) {
+                *group = old_group.clone();
+            }
+        }
+    }
+
+    fn check_tag(&self, tag: &str) -> Result<(), OutboundError> {
+        if self.contains(tag) {
+            return Err(OptionError::DuplicateTag(tag.to_owned()).into());
+        }
+
+        Ok(())
+    }
+
+    // every member and detour must exist, and must not lead back to itself
+    fn check_member(&self, tag: &str, path: &mut Vec<String>) -> Result<(), OutboundError> {
+        if path.iter().any(|t| t == tag) {
+            path.push(tag.to_owned());
+            return Err(OutboundError::Group(format!(
+                "loop ({})",
+                path.join(" -> ")
+            )));
+        }
+
+        let members: Vec<&str> = match (self.group.get(tag), self.outbound.get(tag)) {
+            (Some(group), _) => group.members().iter().map(|m| m.as_str()).collect(),
+            (_, Some(outbound)) => outbound.detour().into_iter().collect(),
+            _ => return Ok(()),
+        };
+
+        path.push(tag.to_owned());
+        for member in members {
+            if !self.contains(member) {
+                return Err(OptionError::UnknownTag(member.to_owned()).into());
+            }
+            self.check_member(member, path)?;
+        }
+        path.pop();
+
+        Ok(())
+    }
+
+    pub fn contains(&self, tag: &str) -> bool {
+        self.outbound.contains_key(tag)
+            || self.reject.contains_key(tag)
+            || self.group.contains_key(tag)
+    }
+
+    /// All tags, sorted.
+    pub fn tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = self
+            .outbound
+            .keys()
+            .chain(self.reject.keys())
+            .chain(self.group.keys())
+            .map(|t| t.as_str())
+            .collect();
+        tags.sort();
+        tags
+    }
+
+    /// Service name of outbound, or kind of reject and group.
+    pub fn name(&self, tag: &str) -> Option<&str> {
+        if let Some(o) = self.outbound.get(tag) {
+            return Some(o.name());
+        }
+        if self.reject.contains_key(tag) {
+            return Some("reject");
+        }
+        self.group.get(tag).map(|g| g.name())
+    }
+
+    pub fn outbound(&self, tag: &str) -> Option<&Outbound> {
+        self.outbound.get(tag)
+    }
+
+    pub fn reject(&self, tag: &str) -> Option<&Reject> {
+        self.reject.get(tag)
+    }
+
+    pub fn group(&self, tag: &str) -> Option<&Group> {
+        self.group.get(tag).map(|g| g.as_ref())
+    }
+
+    /// Switch the member of selector group.
+    pub fn select(&self, group: &str, member: &str) -> Result<(), OutboundError> {
+        match self.group(group) {
+            Some(Group::Selector(s)) => s.select(member),
+            Some(g) => Err(OutboundError::Group(format!(
+                "{} is not selector ({})",
+                group,
+                g.name()
+            ))),
+            None => Err(OptionError::UnknownTag(group.to_owned()).into()),
+        }
+    }
+
+    /// Resolve group to outbound, then connect and handshake.
+    /// Source is the client address, used by some groups to pick member.
+    pub fn dial<'a>(
+        &'a self,
+        tag: &'a str,
+        out_pac: OutboundPacket,
+        source: Option<SocketAddr>,
+    ) -> BoxFuture<'a, Result<Dialed, OutboundError>> {
+        Box::pin(async move {
+            if let Some(outbound) = self.outbound.get(tag) {
+                let stream = match (outbound.detour(), outbound.detour_packet()) {
+                    (Some(detour), Some(server)) => {
+                        tracing::debug!("[outbound] {} detour [{}]", tag, detour);
+                        match self.dial(detour, server, source).await? {
+                            Dialed::Stream { stream, .. } => {
+                                outbound.handshake(stream, out_pac).await?
+                            }
+                            Dialed::Reject(_) => {
+                                return Err(OutboundError::Detour(format!(
+                                    "{} rejected by [{}]",
+                                    tag, detour
+                                )))
+                            }
+                        }
+                    }
+                    _ => outbound.dial(out_pac).await?,
+                };
+                return Ok(Dialed::Stream {
+                    tag: outbound.get_tag(),
+                    stream,
+                });
+            }
+
+            if let Some(reject) = self.reject.get(tag) {
+                return Ok(Dialed::Reject(reject.clone()));
+            }
+
+            match self.group(tag) {
+                Some(Group::Selector(s)) => {
+                    let member = s.selected();
+                    tracing::debug!("[group] selector[{}] -> [{}]", tag, member);
+                    self.dial(&member, out_pac, source).await
+                }
+                Some(Group::UrlTest(u)) => {
+                    let member = u.selected();
+                    tracing::debug!("[group] url_test[{}] -> [{}]", tag, member);
+                    self.dial(&member, out_pac, source).await
+                }
+                Some(Group::LoadBalance(lb)) => {
+                    let (member, guard) = lb.pick(&out_pac.dest, source);
+                    tracing::debug!("[group] load_balance[{}] -> [{}]", tag, member);
+                    match self.dial(member, out_pac, source).await? {
+                        Dialed::Stream { tag, stream } => Ok(Dialed::Stream {
+                            tag,
+                            stream: Box::new(Guarded::new(stream, guard)),
+                        }),
+                        other => Ok(other),
+                    }
+                }
+                Some(Group::Failover(fo)) => {
+                    let mut last_err = None;
+                    for member in fo.candidates() {
+                        tracing::debug!("[group] failover[{}] -> [{}]", tag, member);
+                        match self.dial(member, out_pac.clone(), source).await {
+                            Ok(dialed) => {
+                                fo.report(member, true);
+                                return Ok(dialed);
+                            }
+                            Err(e) => {
+                                tracing::debug!("[group] failover[{}] [{}] {}", tag, member, e);
+                                fo.report(member, false);
+                                last_err = Some(e);
+                            }
+                        }
+                    }
+
+                    Err(last_err
+                        .unwrap_or_else(|| OutboundError::Group(format!("{} has no member", tag))))
+                }
+                Some(Group::Race(race)) => {
+                    let dials = race.outbounds().iter().map(|member| {
+                        let out_pac = out_pac.clone();
+                        Box::pin(async move {
+                            match self.dial(member, out_pac, source).await? {
+                                // reject never wins the race
+                                Dialed::Reject(_) => Err(OutboundError::Group(format!(
+                                    "{} rejected by [{}]",
+                                    tag, member
+                                ))),
+                                dialed => Ok(dialed),
+                            }
+                        })
+                    });
+
+                    // the losers are dropped, which cancels them
+                    let (dialed, _) = select_ok(dials).await?;
+                    if let Dialed::Stream {
+                        tag: ref winner, ..
+                    } = dialed
+                    {
+                        tracing::debug!("[group] race[{}] -> [{}]", tag, winner);
+                    }
+
+                    Ok(dialed)
+                }
+                None => Err(OptionError::UnknownTag(tag.to_owned()).into()),
+            }
+        })
+    }
+
+    /// Spawn background tasks of groups, e.g. url_test probes.
+    pub fn spawn(self: &Arc<Self>) -> Vec<JoinHandle<()>> {
+        let mut tasks = vec![];
+
+        for (tag, group) in self.group.iter() {
+            if let Group::UrlTest(_) = **group {
+                tracing::info!("[group] start url_test[{}] probe", tag);
+                tasks.push(tokio::spawn(self.clone().url_test(tag.to_owned())));
+            }
+        }
+
+        tasks
+    }
+
+    // probe all members every interval, never return
+    async fn url_test(self: Arc<Self>, tag: String) {
+        let Some(Group::UrlTest(group)) = self.group(&tag) else {
+            return;
+        };
+
+        let manager = &self;
+        let tag = &tag;
+        loop {
+            let probes = group.outbounds().iter().map(|member| async move {
+                let latency = url_test::timed(group.timeout(), async {
+                    match manager.dial(member, group.probe_packet(), None).await? {
+                        Dialed::Stream { mut stream, .. } => group
+                            .measure(&mut stream)
+                            .await
+                            .map_err(OutboundError::from),
+                        Dialed::Reject(_) => Err(OutboundError::Group("rejected".into())),
+                    }
+                })
+                .await;
+
+                tracing::debug!(
+                    "[group] url_test[{}] [{}] {}",
+                    tag,
+                    member,
+                    match latency {
+                        Some(l) => format!("{}ms", l.as_millis()),
+                        None => "failed".to_owned(),
+                    }
+                );
+
+                (member.to_owned(), latency)
+            });
+
+            group.update(join_all(probes).await);
+
+            tokio::time::sleep(group.interval()).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use kapibara_service::{Address, OutboundServiceOption, PacketType, ServiceAddress};
+    use kapibara_transport::{
+        option::ClientOption, tcp::TcpClientOption, TlsClientOption, TransportClientOption,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::oneshot,
+        time::Instant,
+    };
+
+    use crate::group::{
+        FailoverOption, GroupKindOption, RaceOption, SelectorOption, UrlTestOption,
+    };
+
+    async fn echo_server() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut r, mut w) = s.split();
+                    let _ = tokio::io::copy(&mut r, &mut w).await;
+                });
+            }
+        });
+
+        addr
+    }
+
+    fn direct(tag: &str, timeout: Option<Duration>) -> OutboundOption {
+        OutboundOption {
+            tag: tag.into(),
+            client: Default::default(),
+            service: OutboundServiceOption::Direct,
+            timeout,
+            detour: None,
+        }
+    }
+
+    fn reject(tag: &str) -> RejectOption {
+        RejectOption {
+            tag: tag.into(),
+            delay: None,
+        }
+    }
+
+    fn race(tag: &str, members: &[&str]) -> GroupOption {
+        GroupOption {
+            tag: tag.into(),
+            opt: GroupKindOption::Race(RaceOption {
+                outbounds: members.iter().map(|m| m.to_string()).collect(),
+            }),
+        }
+    }
+
+    fn packet(addr: SocketAddr) -> OutboundPacket {
+        OutboundPacket {
+            typ: PacketType::Tcp,
+            dest: ServiceAddress::new(Address::Socket(addr.ip()), addr.port()),
+        }
+    }
+
+    fn manager(selector: &[&str], max_failures: u32) -> OutboundManager {
+        let reject = ["a", "b", "c"]
+            .map(|tag| RejectOption {
+                tag: tag.into(),
+                delay: None,
+            })
+            .to_vec();
+        let group = vec![
+            GroupOption {
+                tag: "proxy".into(),
+                opt: GroupKindOption::Selector(SelectorOption {
+                    outbounds: selector.iter().map(|m| m.to_string()).collect(),
+                    default: None,
+                }),
+            },
+            GroupOption {
+                tag: "fo".into(),
+                opt: GroupKindOption::Failover(FailoverOption {
+                    outbounds: vec!["a".into(), "b".into()],
+                    max_failures,
+                    cooldown: Duration::from_secs(60),
+                }),
+            },
+        ];
+
+        OutboundManager::init(vec![], reject, group, &Resolver::default(), Arc::default()).unwrap()
+    }
+
+    fn is_down(manager: &OutboundManager, member: &str) -> bool {
+        match manager.group("fo") {
+            Some(Group::Failover(fo)) => fo.is_down(member),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_inherit() {
+        let old = manager(&["a", "b"], 1);
+        old.select("proxy", "b").unwrap();
+        if let Some(Group::Failover(fo)) = old.group("fo") {
+            fo.report("a", false);
+        }
+        assert!(is_down(&old, "a"));
+
+        // selector changed but still has the member, failover unchanged
+        let mut new = manager(&["a", "b", "c"], 1);
+        new.inherit(&old);
+        assert_eq!(new.group("proxy").unwrap().selected().unwrap(), "b");
+        assert!(is_down(&new, "a"));
+
+        // selected member removed, failover changed
+        let mut new = manager(&["a", "c"], 2);
+        new.inherit(&old);
+        assert_eq!(new.group("proxy").unwrap().selected().unwrap(), "a");
+        assert!(!is_down(&new, "a"));
+    }
+
+    #[tokio::test]
+    async fn test_url_test_probe() {
+        let addr = echo_server().await;
+
+        let direct = direct("direct", Some(Duration::from_secs(5)));
+        let block = reject("block");
+        let group = GroupOption {
+            tag: "auto".into(),
+            opt: GroupKindOption::UrlTest(UrlTestOption {
+                outbounds: vec!["block".into(), "direct".into()],
+                dest: addr.to_string(),
+                payload: Some("ping".into()),
+                interval: Duration::from_secs(300),
+                timeout: Duration::from_secs(1),
+                tolerance: Duration::from_millis(50),
+            }),
+        };
+        let manager = Arc::new(
+            OutboundManager::init(
+                vec![direct],
+                vec![block],
+                vec![group],
+                &Resolver::default(),
+                Arc::default(),
+            )
+            .unwrap(),
+        );
+        let Some(Group::UrlTest(auto)) = manager.group("auto") else {
+            unreachable!()
+        };
+        assert_eq!(auto.selected(), "block");
+
+        let tasks = manager.spawn();
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            while auto.latency("direct").is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        for task in tasks {
+            task.abort();
+        }
+
+        // probed through the outbound, rejected member is unhealthy
+        assert!(auto.latency("direct").is_some());
+        assert_eq!(auto.latency("block"), None);
+        assert_eq!(auto.selected(), "direct");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_race() {
+        let addr = echo_server().await;
+
+        // accept and never answer, report when the client goes away
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (mut s, _) = silent.accept().await.unwrap();
+            let mut buf = vec![];
+            let _ = s.read_to_end(&mut buf).await;
+            let _ = closed_tx.send(());
+        });
+
+        // tls through detour hangs on the silent server until relay times out
+        let slow = OutboundOption {
+            tag: "slow".into(),
+            client: TransportClientOption {
+                opt: ClientOption::Tcp(TcpClientOption {
+                    addr: silent_addr.ip().to_string(),
+                    port: silent_addr.port(),
+                    tcp_nodelay: true,
+                }),
+                tls: Some(TlsClientOption {
+                    insecure: true,
+                    alpn: vec![],
+                    enable_sni: true,
+                    server_name: "localhost".into(),
+                }),
+            },
+            service: OutboundServiceOption::Direct,
+            timeout: None,
+            detour: Some("relay".into()),
+        };
+        let manager = OutboundManager::init(
+            vec![
+                direct("direct", None),
+                direct("relay", Some(Duration::from_secs(30))),
+                slow,
+            ],
+            vec![reject("block"), reject("deny")],
+            vec![
+                race("fast", &["slow", "direct"]),
+                race("safe", &["block", "direct"]),
+                race("none", &["block", "deny"]),
+            ],
+            &Resolver::default(),
+            Arc::default(),
+        )
+        .unwrap();
+
+        // fastest member wins before the slow one times out
+        let start = Instant::now();
+        let Dialed::Stream { tag, mut stream } =
+            manager.dial("fast", packet(addr), None).await.unwrap()
+        else {
+            panic!("race rejected");
+        };
+        assert_eq!(tag, "direct");
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        stream.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // losing dial is dropped, its connection closed
+        tokio::time::timeout(Duration::from_secs(10), closed_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(30));
+
+        // reject never wins
+        match manager.dial("safe", packet(addr), None).await.unwrap() {
+            Dialed::Stream { tag, .. } => assert_eq!(tag, "direct"),
+            Dialed::Reject(_) => panic!("reject won the race"),
+        }
+
+        // all members failed
+        assert!(manager.dial("none", packet(addr), None).await.is_err());
+    }
+}
@@ -0,0 +1,330 @@
+//! Kapibara Metrics
+//!
+//! Counters of dispatch, served in prometheus text format.
+
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hyper::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+
+use crate::{
+    drain::Drain,
+    http,
+    stats::{Stats, StatsSnapshot, TrafficSnapshot},
+    ConnInfo, Tracker,
+};
+
+// upper bounds of dns latency buckets, in seconds
+const DNS_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsOption {
+    pub listen: SocketAddr,
+}
+
+/// Where a connection failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    InboundHandshake,
+    Route,
+    DnsResolve,
+    ClientConnect,
+    OutboundHandshake,
+    // group and detour errors
+    Outbound,
+    Relay,
+}
+
+impl Stage {
+    const ALL: [Stage; 7] = [
+        Self::InboundHandshake,
+        Self::Route,
+        Self::DnsResolve,
+        Self::ClientConnect,
+        Self::OutboundHandshake,
+        Self::Outbound,
+        Self::Relay,
+    ];
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::InboundHandshake => "inbound_handshake",
+            Self::Route => "route",
+            Self::DnsResolve => "dns_resolve",
+            Self::ClientConnect => "client_connect",
+            Self::OutboundHandshake => "outbound_handshake",
+            Self::Outbound => "outbound",
+            Self::Relay => "relay",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    connections: AtomicU64,
+    failures: [AtomicU64; Stage::ALL.len()],
+
+    dns_buckets: [AtomicU64; DNS_BUCKETS.len()],
+    dns_count: AtomicU64,
+    // microseconds
+    dns_sum: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add_connection(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_failure(&self, stage: Stage) {
+        let i = Stage::ALL.iter().position(|s| *s == stage).unwrap_or(0);
+        self.failures[i].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe_dns(&self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        for (i, bound) in DNS_BUCKETS.iter().enumerate() {
+            if secs <= *bound {
+                self.dns_buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.dns_count.fetch_add(1, Ordering::Relaxed);
+        self.dns_sum
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Render in prometheus text format. Active is the number of in-flight
+    /// connections, live are the tracked ones, whose bytes are not in stats yet.
+    pub fn render(&self, active: usize, stats: &Stats, live: &[ConnInfo]) -> String {
+        let mut out = String::new();
+        let load = |n: &AtomicU64| n.load(Ordering::Relaxed);
+
+        header(
+            &mut out,
+            "kapibara_connections_active",
+            "gauge",
+            "Active connections.",
+        );
+        let _ = writeln!(out, "kapibara_connections_active {}", active);
+
+        header(
+            &mut out,
+            "kapibara_connections_total",
+            "counter",
+            "Accepted connections.",
+        );
+        let _ = writeln!(
+            out,
+            "kapibara_connections_total {}",
+            load(&self.connections)
+        );
+
+        header(
+            &mut out,
+            "kapibara_failures_total",
+            "counter",
+            "Failed connections by stage.",
+        );
+        for (stage, n) in Stage::ALL.iter().zip(self.failures.iter()) {
+            let _ = writeln!(
+                out,
+                "kapibara_failures_total{{stage=\"{}\"}} {}",
+                stage.as_str(),
+                load(n)
+            );
+        }
+
+        // totals, so counters do not go back on reset of stats by api
+        let mut snapshot = stats.total();
+        add_live(&mut snapshot, live);
+        traffic(&mut out, "inbound", &snapshot.inbound);
+        traffic(&mut out, "outbound", &snapshot.outbound);
+
+        header(
+            &mut out,
+            "kapibara_dns_resolve_seconds",
+            "histogram",
+            "Latency of dns resolve.",
+        );
+        for (bound, n) in DNS_BUCKETS.iter().zip(self.dns_buckets.iter()) {
+            let _ = writeln!(
+                out,
+                "kapibara_dns_resolve_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                load(n)
+            );
+        }
+        let count = load(&self.dns_count);
+        let _ = writeln!(
+            out,
+            "kapibara_dns_resolve_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "kapibara_dns_resolve_seconds_sum {}",
+            load(&self.dns_sum) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "kapibara_dns_resolve_seconds_count {}", count);
+
+        out
+    }
+}
+
+// bytes of active connections, so long lived ones are counted before close,
+// tagged as stats does for closed ones
+fn add_live(snapshot: &mut StatsSnapshot, live: &[ConnInfo]) {
+    let add = |t: &mut TrafficSnapshot, c: &ConnInfo| {
+        t.upload += c.upload;
+        t.download += c.download;
+    };
+
+    for c in live {
+        add(snapshot.inbound.entry(c.inbound.to_owned()).or_default(), c);
+        add(
+            snapshot.outbound.entry(c.outbound.to_owned()).or_default(),
+            c,
+        );
+        if let Some(ref dialed) = c.dialed {
+            if *dialed != c.outbound {
+                add(snapshot.outbound.entry(dialed.to_owned()).or_default(), c);
+            }
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, typ: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, typ);
+}
+
+// bytes and closed connections by tag
+fn traffic(
+    out: &mut String,
+    label: &str,
+    table: &std::collections::HashMap<String, TrafficSnapshot>,
+) {
+    let mut tags: Vec<&String> = table.keys().collect();
+    tags.sort();
+
+    for (name, help, get) in [
+        (
+            "upload_bytes_total",
+            "Bytes from client",
+            (|t: &TrafficSnapshot| t.upload) as fn(&TrafficSnapshot) -> u64,
+        ),
+        ("download_bytes_total", "Bytes to client", |t| t.download),
+        ("closed_connections_total", "Closed connections", |t| {
+            t.connections
+        }),
+    ] {
+        let name = format!("kapibara_{}_{}", label, name);
+        header(out, &name, "counter", &format!("{} by {}.", help, label));
+        for tag in tags.iter() {
+            let _ = writeln!(
+                out,
+                "{}{{{}=\"{}\"}} {}",
+                name,
+                label,
+                escape(tag),
+                get(&table[*tag])
+            );
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve `GET /metrics` on listen address, never return.
+pub async fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    drain: Arc<Drain>,
+    tracker: Arc<Tracker>,
+    stats: Arc<Stats>,
+) {
+    http::serve(listener, move |req| {
+        let res = if req.method() == Method::GET && req.uri().path() == "/metrics" {
+            http::text(
+                StatusCode::OK,
+                "text/plain; version=0.0.4",
+                metrics.render(drain.active(), &stats, &tracker.list()),
+            )
+        } else {
+            http::not_found()
+        };
+        async move { res }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::stats::StatsKey;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        metrics.add_connection();
+        metrics.add_failure(Stage::ClientConnect);
+        metrics.observe_dns(Duration::from_millis(20));
+
+        let stats = Stats::default();
+        stats.record(
+            &StatsKey {
+                inbound: "in\"1",
+                outbound: &["out"],
+                user: None,
+            },
+            10,
+            20,
+        );
+
+        // still relaying through a group
+        let live = ConnInfo {
+            id: 2,
+            inbound: "in\"1".into(),
+            outbound: "proxy".into(),
+            dialed: Some("out".into()),
+            rule: "final".into(),
+            user: None,
+            source: None,
+            network: "tcp".into(),
+            dest: "example.com:443".into(),
+            start: std::time::SystemTime::now(),
+            upload: 1,
+            download: 2,
+        };
+
+        // reset by api is not seen by metrics
+        stats.reset();
+
+        let text = metrics.render(1, &stats, &[live]);
+        assert!(text.contains("kapibara_connections_active 1\n"));
+        assert!(text.contains("kapibara_connections_total 1\n"));
+        assert!(text.contains("kapibara_failures_total{stage=\"client_connect\"} 1\n"));
+        assert!(text.contains("kapibara_failures_total{stage=\"relay\"} 0\n"));
+        assert!(text.contains("kapibara_inbound_upload_bytes_total{inbound=\"in\\\"1\"} 11\n"));
+        assert!(text.contains("kapibara_outbound_download_bytes_total{outbound=\"out\"} 22\n"));
+        assert!(text.contains("kapibara_outbound_download_bytes_total{outbound=\"proxy\"} 2\n"));
+        assert!(text.contains("kapibara_inbound_closed_connections_total{inbound=\"in\\\"1\"} 1\n"));
+        assert!(text.contains("kapibara_dns_resolve_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(text.contains("kapibara_dns_resolve_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(text.contains("kapibara_dns_resolve_seconds_count 1\n"));
+    }
+}
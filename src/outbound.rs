@@ -1,12 +1,20 @@
 //! Kapibara Outbound
 
-use std::{sync::Arc, time::Duration};
+use std::{net::IpAddr, sync::Arc, time::Duration};
 
-use kapibara_service::{OutboundService, OutboundServiceOption};
-use kapibara_transport::{Resolver, TransportClient, TransportClientOption};
+use kapibara_service::{
+    Address, OutboundPacket, OutboundService, OutboundServiceOption, OutboundServiceTrait,
+    PacketType, ServiceAddress,
+};
+use kapibara_transport::{
+    option::ClientOption, Resolver, TransportClient, TransportClientOption, TransportClientTrait,
+};
 use serde::{Deserialize, Serialize};
 
-use crate::OutboundError;
+use crate::{
+    io::{BoxStream, ToStreamTimer},
+    OutboundError,
+};
 
 fn default_timeout() -> Option<Duration> {
     Some(Duration::from_secs(30))
@@ -22,17 +30,29 @@ pub struct OutboundOption {
     // connect timeout, default 30s
     #[serde(default = "default_timeout")]
     pub timeout: Option<Duration>,
+    // dial the server through another outbound (or group) by tag,
+    // then run tls and transport of client over it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detour: Option<String>,
 }
 
+#[derive(Clone)]
 pub struct Outbound {
     tag: String,
     svc: Arc<OutboundService>,
     cli: Arc<TransportClient>,
     timeout: Option<Duration>,
+    // detour tag and server address
+    detour: Option<(String, ServiceAddress)>,
 }
 
 impl Outbound {
     pub fn init(out_opt: OutboundOption, resolver: &Resolver) -> Result<Self, OutboundError> {
+        let detour = match out_opt.detour {
+            Some(tag) => Some((tag, detour_server(&out_opt.client)?)),
+            None => None,
+        };
+
         let cli = TransportClient::init(out_opt.client, resolver)?;
         let svc = OutboundService::init(out_opt.service)?;
 
@@ -41,6 +61,7 @@ impl Outbound {
             svc: Arc::new(svc),
             cli: Arc::new(cli),
             timeout: out_opt.timeout,
+            detour,
         })
     }
 
@@ -63,4 +84,104 @@ impl Outbound {
     pub fn timeout(&self) -> Option<Duration> {
         self.timeout
     }
+
+    pub fn name(&self) -> &str {
+        self.svc.name()
+    }
+
+    pub fn detour(&self) -> Option<&str> {
+        self.detour.as_ref().map(|(tag, _)| tag.as_str())
+    }
+
+    /// Packet to dial the server through detour.
+    pub fn detour_packet(&self) -> Option<OutboundPacket> {
+        self.detour.as_ref().map(|(_, server)| OutboundPacket {
+            typ: PacketType::Tcp,
+            dest: server.clone(),
+        })
+    }
+
+    /// Run tls and transport of client over a stream connected to
+    /// server by detour, as dial does, then handshake with the packet.
+    pub async fn handshake(
+        &self,
+        stream: BoxStream,
+        out_pac: OutboundPacket,
+    ) -> Result<BoxStream, OutboundError> {
+        let cli_stream = self
+            .cli
+            .connect_stream(stream)
+            .await?
+            .to_timer(self.timeout);
+        let out_stream = self.svc.handshake(cli_stream, out_pac).await?;
+        Ok(Box::new(out_stream))
+    }
+
+    /// Connect to server and handshake with the packet.
+    pub async fn dial(&self, out_pac: OutboundPacket) -> Result<BoxStream, OutboundError> {
+        let cli_stream = self.cli.connect().await?;
+
+        // if cli_stream is empty, so the timer need to set after handshake
+        // else cli_stream need to set timer first, because handshake need.
+        if cli_stream.is_emtpy() {
+            let out_stream = self.svc.handshake(cli_stream, out_pac).await?;
+            Ok(Box::new(out_stream.to_timer(self.timeout)))
+        } else {
+            let cli_stream = cli_stream.to_timer(self.timeout);
+            let out_stream = self.svc.handshake(cli_stream, out_pac).await?;
+            Ok(Box::new(out_stream))
+        }
+    }
+}
+
+// server of client, dialed by detour
+fn detour_server(client: &TransportClientOption) -> Result<ServiceAddress, OutboundError> {
+    let (addr, port) = match client.opt {
+        ClientOption::Tcp(ref tcp) => (&tcp.addr, tcp.port),
+        ClientOption::Ws(ref ws) => (&ws.addr, ws.port),
+        ClientOption::Empty => return Err(OutboundError::Detour("client has no server".into())),
+    };
+
+    let addr = match addr.parse::<IpAddr>() {
+        Ok(ip) => Address::Socket(ip),
+        Err(_) => Address::Domain(addr.to_owned()),
+    };
+    Ok(ServiceAddress::new(addr, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use kapibara_transport::{tcp::TcpClientOption, websocket::WebSocketClientOption};
+
+    #[test]
+    fn test_detour_server() {
+        let mut client = TransportClientOption {
+            opt: ClientOption::Tcp(TcpClientOption {
+                addr: "127.0.0.1".into(),
+                port: 1080,
+                tcp_nodelay: true,
+            }),
+            tls: None,
+        };
+        assert_eq!(
+            detour_server(&client).unwrap(),
+            ServiceAddress::new(Address::Socket("127.0.0.1".parse().unwrap()), 1080)
+        );
+
+        client.opt = ClientOption::Ws(WebSocketClientOption {
+            addr: "example.com".into(),
+            port: 443,
+            path: "/ws".into(),
+            tcp_nodelay: true,
+        });
+        assert_eq!(
+            detour_server(&client).unwrap(),
+            ServiceAddress::new(Address::Domain("example.com".into()), 443)
+        );
+
+        client.opt = ClientOption::Empty;
+        assert!(detour_server(&client).is_err());
+    }
 }
@@ -0,0 +1,115 @@
+//! Kapibara Reject
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectOption {
+    pub tag: String,
+    // hold the connection before close (tarpit), default close immediately
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delay: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reject {
+    tag: String,
+    delay: Option<Duration>,
+}
+
+impl Reject {
+    pub fn init(opt: RejectOption) -> Self {
+        Self {
+            tag: opt.tag,
+            delay: opt.delay,
+        }
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn get_tag(&self) -> String {
+        self.tag.to_owned()
+    }
+
+    pub fn delay(&self) -> Option<Duration> {
+        self.delay
+    }
+
+    /// Discard everything from client until delay expired or client closed,
+    /// then drop the stream.
+    pub async fn reject<S>(&self, mut stream: S)
+    where
+        S: AsyncRead + Unpin,
+    {
+        let Some(delay) = self.delay else {
+            return;
+        };
+
+        let _ = tokio::time::timeout(delay, async {
+            let mut buf = [0u8; 1024];
+            while let Ok(n) = stream.read(&mut buf).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        })
+        .await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::{io::AsyncWriteExt, time::Instant};
+
+    fn reject(delay: Option<Duration>) -> Reject {
+        Reject::init(RejectOption {
+            tag: "block".into(),
+            delay,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reject_no_delay() {
+        let (_client, server) = tokio::io::duplex(64);
+
+        let start = Instant::now();
+        reject(None).reject(server).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reject_delay() {
+        let (mut client, server) = tokio::io::duplex(64);
+
+        // client keeps sending, all discarded
+        tokio::spawn(async move {
+            while client.write_all(b"hello").await.is_ok() {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        });
+
+        let start = Instant::now();
+        reject(Some(Duration::from_secs(10))).reject(server).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reject_client_close() {
+        let (client, server) = tokio::io::duplex(64);
+
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            drop(client);
+        });
+
+        let start = Instant::now();
+        reject(Some(Duration::from_secs(10))).reject(server).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+}
@@ -0,0 +1,112 @@
+//! Route Domain Matcher
+
+use std::collections::HashSet;
+
+use regex::Regex;
+
+use crate::RouteError;
+
+#[derive(Debug, Clone, Default)]
+pub struct DomainMatcher {
+    full: HashSet<String>,
+    suffix: Vec<String>,
+    keyword: Vec<String>,
+    regex: Vec<Regex>,
+}
+
+impl DomainMatcher {
+    pub fn new(
+        full: &[String],
+        suffix: &[String],
+        keyword: &[String],
+        regex: &[String],
+    ) -> Result<Self, RouteError> {
+        let regex = regex
+            .iter()
+            .map(|r| Regex::new(r))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            full: full.iter().map(|d| normalize(d)).collect(),
+            suffix: suffix
+                .iter()
+                .map(|d| normalize(d.trim_start_matches('.')))
+                .collect(),
+            keyword: keyword.iter().map(|k| k.to_ascii_lowercase()).collect(),
+            regex,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.full.is_empty()
+            && self.suffix.is_empty()
+            && self.keyword.is_empty()
+            && self.regex.is_empty()
+    }
+
+    pub fn is_match(&self, domain: &str) -> bool {
+        let domain = normalize(domain);
+
+        if self.full.contains(&domain) {
+            return true;
+        }
+
+        // suffix `example.com` matches `example.com` and `*.example.com`
+        // but not `notexample.com`
+        for suffix in self.suffix.iter() {
+            if let Some(rest) = domain.strip_suffix(suffix.as_str()) {
+                if rest.is_empty() || rest.ends_with('.') {
+                    return true;
+                }
+            }
+        }
+
+        if self.keyword.iter().any(|k| domain.contains(k.as_str())) {
+            return true;
+        }
+
+        self.regex.iter().any(|r| r.is_match(&domain))
+    }
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_domain_matcher() {
+        let matcher = DomainMatcher::new(
+            &strings(&["full.com"]),
+            &strings(&["example.com", ".suffix.org"]),
+            &strings(&["google"]),
+            &strings(&[r"^ads?\d*\."]),
+        )
+        .unwrap();
+
+        assert!(matcher.is_match("full.com"));
+        assert!(matcher.is_match("FULL.com."));
+        assert!(!matcher.is_match("a.full.com"));
+
+        assert!(matcher.is_match("example.com"));
+        assert!(matcher.is_match("www.example.com"));
+        assert!(!matcher.is_match("notexample.com"));
+        assert!(matcher.is_match("a.b.suffix.org"));
+
+        assert!(matcher.is_match("www.google.co.jp"));
+        assert!(matcher.is_match("ad1.tracker.net"));
+        assert!(!matcher.is_match("bad.tracker.net"));
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(DomainMatcher::new(&[], &[], &[], &strings(&["("])).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+//! Route GeoIp Matcher
+
+use std::{collections::HashSet, net::IpAddr, path::Path, sync::Arc};
+
+use maxminddb::{geoip2, Reader};
+use serde::{Deserialize, Serialize};
+
+use crate::RouteError;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GeoIpOption {
+    // maxmind database (.mmdb) with country data
+    pub path: String,
+}
+
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, RouteError> {
+        let reader = Reader::open_readfile(path.as_ref())
+            .map_err(|e| RouteError::GeoIp(format!("open {} ({})", path.as_ref().display(), e)))?;
+
+        Ok(Self { reader })
+    }
+
+    /// Upper case iso code of the country, fallback to registered country.
+    /// Ipv4-mapped ipv6 address is looked up as ipv4, as ip cidr does.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        let country: geoip2::Country = self.reader.lookup(ip.to_canonical()).ok()?;
+
+        country
+            .country
+            .and_then(|c| c.iso_code)
+            .or(country.registered_country.and_then(|c| c.iso_code))
+            .map(|code| code.to_ascii_uppercase())
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct GeoIpMatcher {
+    db: Option<Arc<GeoIp>>,
+    codes: HashSet<String>,
+}
+
+impl GeoIpMatcher {
+    pub fn new(db: Option<Arc<GeoIp>>, codes: &[String]) -> Result<Self, RouteError> {
+        if !codes.is_empty() && db.is_none() {
+            return Err(RouteError::GeoIp("database not configured".into()));
+        }
+
+        Ok(Self {
+            db,
+            codes: codes.iter().map(|c| c.to_ascii_uppercase()).collect(),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.codes.is_empty()
+    }
+
+    pub fn is_match(&self, ip: &IpAddr) -> bool {
+        let Some(ref db) = self.db else {
+            return false;
+        };
+
+        match db.country(*ip) {
+            Some(code) => self.codes.contains(&code),
+            None => false,
+        }
+    }
+}
+
+impl std::fmt::Debug for GeoIpMatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIpMatcher")
+            .field("codes", &self.codes)
+            .finish()
+    }
+}
@@ -0,0 +1,132 @@
+//! Route Ip and Port Matcher
+
+use std::{collections::HashSet, net::IpAddr, ops::RangeInclusive};
+
+use ipnet::IpNet;
+
+use crate::RouteError;
+
+#[derive(Debug, Clone, Default)]
+pub struct IpMatcher {
+    cidr: Vec<IpNet>,
+}
+
+impl IpMatcher {
+    /// Accept `10.0.0.0/8`, `fd00::/8` or a single address.
+    pub fn new(cidr: &[String]) -> Result<Self, RouteError> {
+        let cidr = cidr
+            .iter()
+            .map(|c| match c.parse::<IpAddr>() {
+                Ok(ip) => Ok(IpNet::from(ip)),
+                Err(_) => c.parse::<IpNet>(),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self { cidr })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cidr.is_empty()
+    }
+
+    pub fn is_match(&self, ip: &IpAddr) -> bool {
+        let ip = to_canonical(ip);
+        self.cidr.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PortMatcher {
+    port: HashSet<u16>,
+    range: Vec<RangeInclusive<u16>>,
+}
+
+impl PortMatcher {
+    /// Range is written as `start-end`, both inclusive.
+    pub fn new(port: &[u16], range: &[String]) -> Result<Self, RouteError> {
+        let range = range
+            .iter()
+            .map(|r| parse_range(r).ok_or(RouteError::PortRange(r.to_owned())))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            port: port.iter().copied().collect(),
+            range,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.port.is_empty() && self.range.is_empty()
+    }
+
+    pub fn is_match(&self, port: u16) -> bool {
+        self.port.contains(&port) || self.range.iter().any(|r| r.contains(&port))
+    }
+}
+
+fn parse_range(s: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = s.split_once('-')?;
+    let start = start.trim().parse::<u16>().ok()?;
+    let end = end.trim().parse::<u16>().ok()?;
+
+    if start > end {
+        return None;
+    }
+
+    Some(start..=end)
+}
+
+// ipv4-mapped ipv6 address should match ipv4 cidr
+fn to_canonical(ip: &IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => *ip,
+        },
+        IpAddr::V4(_) => *ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(s: &[&str]) -> Vec<String> {
+        s.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ip_matcher() {
+        let matcher = IpMatcher::new(&strings(&[
+            "10.0.0.0/8",
+            "192.168.0.0/16",
+            "1.1.1.1",
+            "fd00::/8",
+        ]))
+        .unwrap();
+
+        assert!(matcher.is_match(&"10.1.2.3".parse().unwrap()));
+        assert!(matcher.is_match(&"192.168.1.1".parse().unwrap()));
+        assert!(matcher.is_match(&"1.1.1.1".parse().unwrap()));
+        assert!(!matcher.is_match(&"1.1.1.2".parse().unwrap()));
+        assert!(matcher.is_match(&"fd12::1".parse().unwrap()));
+        assert!(matcher.is_match(&"::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!matcher.is_match(&"2001:db8::1".parse().unwrap()));
+
+        assert!(IpMatcher::new(&strings(&["10.0.0.0/33"])).is_err());
+    }
+
+    #[test]
+    fn test_port_matcher() {
+        let matcher = PortMatcher::new(&[25], &strings(&["1000-2000"])).unwrap();
+
+        assert!(matcher.is_match(25));
+        assert!(matcher.is_match(1000));
+        assert!(matcher.is_match(2000));
+        assert!(!matcher.is_match(2001));
+        assert!(!matcher.is_match(443));
+
+        assert!(PortMatcher::new(&[], &strings(&["2000-1000"])).is_err());
+        assert!(PortMatcher::new(&[], &strings(&["80"])).is_err());
+    }
+}
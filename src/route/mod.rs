@@ -0,0 +1,635 @@
+//! Kapibara Route
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use kapibara_service::{Address, ServiceAddress};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{OptionError, RouteError};
+
+pub mod domain;
+pub use domain::DomainMatcher;
+
+pub mod ip;
+pub use ip::{IpMatcher, PortMatcher};
+
+pub mod geoip;
+pub use geoip::{GeoIp, GeoIpMatcher, GeoIpOption};
+
+pub mod rule_set;
+pub use rule_set::{RuleSet, RuleSetFormat, RuleSetOption};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RouteOption {
+    // first matched rule wins
+    #[serde(default)]
+    pub rules: Vec<RouteRuleOption>,
+    // outbound for connection that matches no rule
+    #[serde(rename = "final", default, skip_serializing_if = "Option::is_none")]
+    pub final_outbound: Option<String>,
+    // loaded once, required by `geoip` condition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geoip: Option<GeoIpOption>,
+    // external domain/cidr lists, reloaded when changed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rule_set: Vec<RuleSetOption>,
+    #[serde(default)]
+    pub domain_strategy: DomainStrategy,
+}
+
+/// How ip conditions apply to domain destination.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainStrategy {
+    // ip conditions never match domain
+    #[default]
+    AsIs,
+    // resolve domain and match again if no rule matched
+    IpIfNonMatch,
+    // resolve domain before matching
+    IpOnDemand,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct RouteRuleOption {
+    #[serde(default)]
+    pub dns: bool,
+    // empty means any inbound
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub inbound: Vec<String>,
+    pub outbound: String,
+    // user authenticated by inbound
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub user: Vec<String>,
+    // full domain
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domain: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domain_suffix: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domain_keyword: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub domain_regex: Vec<String>,
+    // ipv4 or ipv6 cidr of destination
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ip_cidr: Vec<String>,
+    // country code of destination ip, e.g. CN
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub geoip: Vec<String>,
+    // tag of rule set, destination matches any of them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rule_set: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port: Vec<u16>,
+    // e.g. 1000-2000
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub port_range: Vec<String>,
+    // client address, unknown source never matches
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_ip_cidr: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_port: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_port_range: Vec<String>,
+}
+
+pub struct Route {
+    pub rules: Vec<RouteRule>,
+    pub final_outbound: Option<String>,
+    pub rule_set: Vec<Arc<RuleSet>>,
+    pub domain_strategy: DomainStrategy,
+}
+
+/// Everything known about a connection when routing.
+#[derive(Debug, Clone, Copy)]
+pub struct RouteContext<'a> {
+    pub inbound: &'a str,
+    pub dest: &'a ServiceAddress,
+    pub user: Option<&'a str>,
+    pub source: Option<SocketAddr>,
+    // domain sniffed from ip destination
+    pub sniffed: Option<&'a str>,
+    // ip of domain destination, by domain strategy
+    pub resolved: Option<IpAddr>,
+}
+
+impl RouteContext<'_> {
+    /// Domain of destination, or the sniffed one if destination is ip.
+    pub fn domain(&self) -> Option<&str> {
+        match self.dest.addr {
+            Address::Domain(ref domain) => Some(domain),
+            Address::Socket(_) => self.sniffed,
+        }
+    }
+
+    /// Ip of destination, or the resolved one if destination is domain.
+    pub fn ip(&self) -> Option<IpAddr> {
+        match self.dest.addr {
+            Address::Socket(ip) => Some(ip),
+            Address::Domain(_) => self.resolved,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RouteDecision<'a> {
+    // index of the matched rule, none if fall to final
+    pub index: Option<usize>,
+    pub outbound: &'a str,
+    pub dns: bool,
+}
+
+impl std::fmt::Display for RouteDecision<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.index {
+            Some(i) => write!(f, "rule#{}", i),
+            None => write!(f, "final"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RouteRule {
+    pub dns: bool,
+    pub outbound: String,
+    pub inbound: HashSet<String>,
+    pub user: HashSet<String>,
+    pub domain: DomainMatcher,
+    pub ip: IpMatcher,
+    pub geoip: GeoIpMatcher,
+    pub rule_set: Vec<Arc<RuleSet>>,
+    pub port: PortMatcher,
+    pub source_ip: IpMatcher,
+    pub source_port: PortMatcher,
+}
+
+impl RouteRule {
+    pub fn init(
+        option: &RouteRuleOption,
+        geoip: Option<Arc<GeoIp>>,
+        rule_set: &HashMap<String, Arc<RuleSet>>,
+    ) -> Result<Self, RouteError> {
+        let domain = DomainMatcher::new(
+            &option.domain,
+            &option.domain_suffix,
+            &option.domain_keyword,
+            &option.domain_regex,
+        )?;
+        let ip = IpMatcher::new(&option.ip_cidr)?;
+        let geoip = GeoIpMatcher::new(geoip, &option.geoip)?;
+        let rule_set = option
+            .rule_set
+            .iter()
+            .map(|tag| {
+                rule_set
+                    .get(tag)
+                    .cloned()
+                    .ok_or(OptionError::UnknownTag(tag.to_owned()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let port = PortMatcher::new(&option.port, &option.port_range)?;
+        let source_ip = IpMatcher::new(&option.source_ip_cidr)?;
+        let source_port = PortMatcher::new(&option.source_port, &option.source_port_range)?;
+
+        Ok(Self {
+            dns: option.dns,
+            outbound: option.outbound.clone(),
+            inbound: option.inbound.iter().cloned().collect(),
+            user: option.user.iter().cloned().collect(),
+            domain,
+            ip,
+            geoip,
+            rule_set,
+            port,
+            source_ip,
+            source_port,
+        })
+    }
+
+    /// Every configured condition must match (AND),
+    /// rule without any condition matches every connection.
+    pub fn is_match(&self, ctx: &RouteContext) -> bool {
+        if !self.inbound.is_empty() && !self.inbound.contains(ctx.inbound) {
+            return false;
+        }
+
+        if !self.user.is_empty() {
+            match ctx.user {
+                Some(user) if self.user.contains(user) => {}
+                _ => return false,
+            }
+        }
+
+        let dest = ctx.dest;
+
+        if !self.domain.is_empty() {
+            let matched = match ctx.domain() {
+                Some(domain) => self.domain.is_match(domain),
+                None => false,
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.ip.is_empty() {
+            let matched = match ctx.ip() {
+                Some(ip) => self.ip.is_match(&ip),
+                None => false,
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.geoip.is_empty() {
+            let matched = match ctx.ip() {
+                Some(ip) => self.geoip.is_match(&ip),
+                None => false,
+            };
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.rule_set.is_empty() {
+            let matched = self.rule_set.iter().any(|r| {
+                ctx.domain().is_some_and(|d| r.is_match_domain(d))
+                    || ctx.ip().is_some_and(|ip| r.is_match_ip(&ip))
+            });
+            if !matched {
+                return false;
+            }
+        }
+
+        if !self.port.is_empty() && !self.port.is_match(dest.port) {
+            return false;
+        }
+
+        if !self.source_ip.is_empty() {
+            match ctx.source {
+                Some(addr) if self.source_ip.is_match(&addr.ip()) => {}
+                _ => return false,
+            }
+        }
+
+        if !self.source_port.is_empty() {
+            match ctx.source {
+                Some(addr) if self.source_port.is_match(addr.port()) => {}
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    // rule sets are asked each time, as reload may add or remove ip entries
+    pub fn has_ip_condition(&self) -> bool {
+        !self.ip.is_empty() || !self.geoip.is_empty() || self.rule_set.iter().any(|r| r.has_ip())
+    }
+}
+
+impl Route {
+    pub fn init(option: RouteOption) -> Result<Self, RouteError> {
+        let geoip = match option.geoip {
+            Some(opt) => Some(Arc::new(GeoIp::open(opt.path)?)),
+            None => None,
+        };
+
+        let mut rule_set = HashMap::new();
+        for opt in option.rule_set {
+            let r = Arc::new(RuleSet::init(opt)?);
+            if let Some(other) = rule_set.insert(r.tag().to_owned(), r) {
+                return Err(OptionError::DuplicateTag(other.tag().to_owned()).into());
+            }
+        }
+
+        let rules = option
+            .rules
+            .iter()
+            .map(|rule| RouteRule::init(rule, geoip.clone(), &rule_set))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            rules,
+            final_outbound: option.final_outbound,
+            rule_set: rule_set.into_values().collect(),
+            domain_strategy: option.domain_strategy,
+        })
+    }
+
+    /// Whether domain destination should be resolved before matching.
+    pub fn resolve_first(&self) -> bool {
+        self.domain_strategy == DomainStrategy::IpOnDemand
+            && self.rules.iter().any(|r| r.has_ip_condition())
+    }
+
+    /// Whether domain destination should be resolved after nothing matched.
+    pub fn resolve_non_match(&self) -> bool {
+        self.domain_strategy == DomainStrategy::IpIfNonMatch
+            && self.rules.iter().any(|r| r.has_ip_condition())
+    }
+
+    /// Return the first matched rule, or the final outbound.
+    pub fn decide(&self, ctx: &RouteContext) -> Option<RouteDecision<'_>> {
+        for (index, rule) in self.rules.iter().enumerate() {
+            if rule.is_match(ctx) {
+                return Some(RouteDecision {
+                    index: Some(index),
+                    outbound: &rule.outbound,
+                    dns: rule.dns,
+                });
+            }
+        }
+
+        self.final_outbound.as_ref().map(|outbound| RouteDecision {
+            index: None,
+            outbound,
+            dns: false,
+        })
+    }
+
+    pub fn inbounds(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .flat_map(|rule| rule.inbound.iter())
+            .map(|tag| tag.as_str())
+    }
+
+    pub fn outbounds(&self) -> impl Iterator<Item = &str> {
+        self.rules
+            .iter()
+            .map(|rule| rule.outbound.as_str())
+            .chain(self.final_outbound.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(inbound: &[&str], outbound: &str) -> RouteRuleOption {
+        RouteRuleOption {
+            inbound: inbound.iter().map(|s| s.to_string()).collect(),
+            outbound: outbound.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_route_decide() {
+        let route = Route::init(RouteOption {
+            rules: vec![
+                RouteRuleOption {
+                    domain_suffix: vec!["example.com".into()],
+                    ..rule(&["in-1"], "out-1")
+                },
+                RouteRuleOption {
+                    port: vec![25],
+                    ..rule(&[], "block")
+                },
+                RouteRuleOption {
+                    ip_cidr: vec!["10.0.0.0/8".into()],
+                    ..rule(&["in-1", "in-2"], "out-2")
+                },
+            ],
+            final_outbound: Some("out-3".into()),
+            geoip: None,
+            rule_set: vec![],
+            domain_strategy: DomainStrategy::AsIs,
+        })
+        .unwrap();
+
+        let domain = ServiceAddress::new(Address::Domain("www.example.com".into()), 443);
+        let smtp = ServiceAddress::new(Address::Domain("www.example.com".into()), 25);
+        let lan = ServiceAddress::new(Address::Socket("10.0.0.1".parse().unwrap()), 80);
+
+        let decide = |inbound, dest| {
+            let d = route
+                .decide(&RouteContext {
+                    inbound,
+                    dest,
+                    user: None,
+                    source: None,
+                    sniffed: None,
+                    resolved: None,
+                })
+                .unwrap();
+            (d.index, d.outbound.to_owned())
+        };
+
+        assert_eq!(decide("in-1", &domain), (Some(0), "out-1".into()));
+        assert_eq!(decide("in-2", &domain), (None, "out-3".into()));
+        assert_eq!(decide("in-2", &smtp), (Some(1), "block".into()));
+        assert_eq!(decide("in-2", &lan), (Some(2), "out-2".into()));
+        assert_eq!(decide("in-3", &lan), (None, "out-3".into()));
+    }
+
+    #[test]
+    fn test_route_resolved() {
+        let route = Route::init(RouteOption {
+            rules: vec![RouteRuleOption {
+                ip_cidr: vec!["10.0.0.0/8".into()],
+                ..rule(&[], "out-1")
+            }],
+            final_outbound: Some("out-2".into()),
+            geoip: None,
+            rule_set: vec![],
+            domain_strategy: DomainStrategy::IpIfNonMatch,
+        })
+        .unwrap();
+
+        assert!(route.resolve_non_match());
+        assert!(!route.resolve_first());
+
+        let dest = ServiceAddress::new(Address::Domain("lan.example.com".into()), 443);
+        let decide = |resolved: Option<&str>| {
+            route
+                .decide(&RouteContext {
+                    inbound: "in-1",
+                    dest: &dest,
+                    user: None,
+                    source: None,
+                    sniffed: None,
+                    resolved: resolved.map(|s| s.parse().unwrap()),
+                })
+                .unwrap()
+                .outbound
+                .to_owned()
+        };
+
+        assert_eq!(decide(None), "out-2");
+        assert_eq!(decide(Some("10.0.0.1")), "out-1");
+        assert_eq!(decide(Some("8.8.8.8")), "out-2");
+    }
+
+    #[test]
+    fn test_route_rule_set_resolve() {
+        let path =
+            std::env::temp_dir().join(format!("kapibara-route-rule-set-{}", std::process::id()));
+        std::fs::write(&path, "example.com\n").unwrap();
+
+        let route = Route::init(RouteOption {
+            rules: vec![RouteRuleOption {
+                rule_set: vec!["set".into()],
+                ..rule(&[], "out-1")
+            }],
+            final_outbound: Some("out-2".into()),
+            geoip: None,
+            rule_set: vec![RuleSetOption {
+                tag: "set".into(),
+                path: path.to_string_lossy().into(),
+                format: RuleSetFormat::Text,
+                interval: std::time::Duration::from_secs(60),
+            }],
+            domain_strategy: DomainStrategy::IpIfNonMatch,
+        })
+        .unwrap();
+
+        // only domain entries, nothing to resolve for
+        assert!(!route.resolve_non_match());
+
+        std::fs::write(&path, "example.com\n10.0.0.0/8\n").unwrap();
+        assert!(route.rule_set[0].reload().unwrap());
+        assert!(route.resolve_non_match());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_route_user() {
+        let route = Route::init(RouteOption {
+            rules: vec![RouteRuleOption {
+                user: vec!["alice".into()],
+                ..rule(&[], "out-1")
+            }],
+            final_outbound: Some("out-2".into()),
+            geoip: None,
+            rule_set: vec![],
+            domain_strategy: DomainStrategy::AsIs,
+        })
+        .unwrap();
+
+        let dest = ServiceAddress::new(Address::Domain("example.com".into()), 443);
+        let decide = |user| {
+            route
+                .decide(&RouteContext {
+                    inbound: "in-1",
+                    dest: &dest,
+                    user,
+                    source: None,
+                    sniffed: None,
+                    resolved: None,
+                })
+                .unwrap()
+                .outbound
+                .to_owned()
+        };
+
+        assert_eq!(decide(Some("alice")), "out-1");
+        assert_eq!(decide(Some("bob")), "out-2");
+        assert_eq!(decide(None), "out-2");
+    }
+
+    #[test]
+    fn test_route_geoip_without_database() {
+        let result = Route::init(RouteOption {
+            rules: vec![RouteRuleOption {
+                geoip: vec!["cn".into()],
+                ..rule(&[], "out-1")
+            }],
+            final_outbound: None,
+            geoip: None,
+            rule_set: vec![],
+            domain_strategy: DomainStrategy::AsIs,
+        });
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_route_geoip() {
+        // 1.1.1.0/24 is AU, 2001:db8::/32 is JP, no alias of ipv4-mapped ipv6
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/route/testdata/country.mmdb"
+        );
+        let route = Route::init(RouteOption {
+            rules: vec![
+                RouteRuleOption {
+                    geoip: vec!["au".into()],
+                    ..rule(&[], "out-1")
+                },
+                RouteRuleOption {
+                    geoip: vec!["JP".into()],
+                    ..rule(&[], "out-2")
+                },
+            ],
+            final_outbound: Some("out-3".into()),
+            geoip: Some(GeoIpOption { path: path.into() }),
+            rule_set: vec![],
+            domain_strategy: DomainStrategy::AsIs,
+        })
+        .unwrap();
+
+        let decide = |ip: &str| {
+            let dest = ServiceAddress::new(Address::Socket(ip.parse().unwrap()), 443);
+            route
+                .decide(&RouteContext {
+                    inbound: "in-1",
+                    dest: &dest,
+                    user: None,
+                    source: None,
+                    sniffed: None,
+                    resolved: None,
+                })
+                .unwrap()
+                .outbound
+                .to_owned()
+        };
+
+        assert_eq!(decide("1.1.1.1"), "out-1");
+        assert_eq!(decide("::ffff:1.1.1.1"), "out-1");
+        assert_eq!(decide("2001:db8::1"), "out-2");
+        assert_eq!(decide("8.8.8.8"), "out-3");
+    }
+
+    #[test]
+    fn test_route_source() {
+        let route = Route::init(RouteOption {
+            rules: vec![RouteRuleOption {
+                source_ip_cidr: vec!["192.168.0.0/16".into()],
+                ..rule(&[], "out-1")
+            }],
+            final_outbound: Some("out-2".into()),
+            geoip: None,
+            rule_set: vec![],
+            domain_strategy: DomainStrategy::AsIs,
+        })
+        .unwrap();
+
+        let dest = ServiceAddress::new(Address::Domain("example.com".into()), 443);
+        let decide = |source: Option<&str>| {
+            route
+                .decide(&RouteContext {
+                    inbound: "in-1",
+                    dest: &dest,
+                    user: None,
+                    source: source.map(|s| s.parse().unwrap()),
+                    sniffed: None,
+                    resolved: None,
+                })
+                .unwrap()
+                .outbound
+                .to_owned()
+        };
+
+        assert_eq!(decide(Some("192.168.1.2:5000")), "out-1");
+        assert_eq!(decide(Some("8.8.8.8:5000")), "out-2");
+        assert_eq!(decide(None), "out-2");
+    }
+}
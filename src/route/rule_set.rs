@@ -0,0 +1,396 @@
+//! Route Rule Set
+//!
+//! Text format, one entry per line, `#` starts a comment:
+//!
+//! ```text
+//! domain:example.com
+//! domain_suffix:google.com
+//! domain_keyword:ads
+//! domain_regex:^tracker\d+\.
+//! ip_cidr:10.0.0.0/8
+//! # entry without prefix is ip_cidr if it parses, else domain_suffix
+//! example.org
+//! 192.168.0.0/16
+//! ```
+//!
+//! Binary format is `KRS1` followed by records of
+//! `kind (u8) | length (u16 be) | value`, see [`encode`].
+
+use std::{
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+
+use crate::RouteError;
+
+use super::{DomainMatcher, IpMatcher};
+
+const MAGIC: &[u8; 4] = b"KRS1";
+
+fn default_interval() -> Duration {
+    Duration::from_secs(60)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleSetOption {
+    pub tag: String,
+    pub path: String,
+    #[serde(default)]
+    pub format: RuleSetFormat,
+    // how often to check the file for changes, default 60s
+    #[serde(default = "default_interval")]
+    pub interval: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RuleSetFormat {
+    #[default]
+    Text,
+    Binary,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EntryKind {
+    Domain = 1,
+    DomainSuffix = 2,
+    DomainKeyword = 3,
+    DomainRegex = 4,
+    IpCidr = 5,
+}
+
+impl EntryKind {
+    fn from_u8(n: u8) -> Option<Self> {
+        match n {
+            1 => Some(Self::Domain),
+            2 => Some(Self::DomainSuffix),
+            3 => Some(Self::DomainKeyword),
+            4 => Some(Self::DomainRegex),
+            5 => Some(Self::IpCidr),
+            _ => None,
+        }
+    }
+
+    fn from_prefix(s: &str) -> Option<Self> {
+        match s {
+            "domain" => Some(Self::Domain),
+            "domain_suffix" => Some(Self::DomainSuffix),
+            "domain_keyword" => Some(Self::DomainKeyword),
+            "domain_regex" => Some(Self::DomainRegex),
+            "ip_cidr" => Some(Self::IpCidr),
+            _ => None,
+        }
+    }
+}
+
+/// Parsed entries of a rule set file.
+#[derive(Debug, Clone, Default)]
+pub struct RuleSetEntries {
+    pub domain: Vec<String>,
+    pub domain_suffix: Vec<String>,
+    pub domain_keyword: Vec<String>,
+    pub domain_regex: Vec<String>,
+    pub ip_cidr: Vec<String>,
+}
+
+impl RuleSetEntries {
+    fn push(&mut self, kind: EntryKind, value: String) {
+        match kind {
+            EntryKind::Domain => self.domain.push(value),
+            EntryKind::DomainSuffix => self.domain_suffix.push(value),
+            EntryKind::DomainKeyword => self.domain_keyword.push(value),
+            EntryKind::DomainRegex => self.domain_regex.push(value),
+            EntryKind::IpCidr => self.ip_cidr.push(value),
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = (EntryKind, &String)> {
+        [
+            (EntryKind::Domain, &self.domain),
+            (EntryKind::DomainSuffix, &self.domain_suffix),
+            (EntryKind::DomainKeyword, &self.domain_keyword),
+            (EntryKind::DomainRegex, &self.domain_regex),
+            (EntryKind::IpCidr, &self.ip_cidr),
+        ]
+        .into_iter()
+        .flat_map(|(kind, values)| values.iter().map(move |v| (kind, v)))
+    }
+
+    pub fn parse_text(text: &str) -> Result<Self, RouteError> {
+        let mut entries = Self::default();
+
+        for line in text.lines() {
+            let line = match line.split_once('#') {
+                Some((l, _)) => l,
+                None => line,
+            }
+            .trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let prefixed = line.split_once(':').and_then(|(prefix, value)| {
+                Some((EntryKind::from_prefix(prefix.trim())?, value.trim()))
+            });
+
+            let (kind, value) = match prefixed {
+                Some(entry) => entry,
+                // ipv6 address also contains ':'
+                None if line.parse::<IpAddr>().is_ok() || line.parse::<IpNet>().is_ok() => {
+                    (EntryKind::IpCidr, line)
+                }
+                None if !line.contains(':') => (EntryKind::DomainSuffix, line),
+                None => {
+                    return Err(RouteError::RuleSet(format!("invalid entry ({})", line)));
+                }
+            };
+
+            entries.push(kind, value.to_owned());
+        }
+
+        Ok(entries)
+    }
+
+    pub fn parse_binary(data: &[u8]) -> Result<Self, RouteError> {
+        let invalid = || RouteError::RuleSet("invalid binary rule set".into());
+
+        let mut data = data.strip_prefix(MAGIC).ok_or_else(invalid)?;
+        let mut entries = Self::default();
+
+        while !data.is_empty() {
+            if data.len() < 3 {
+                return Err(invalid());
+            }
+
+            let kind = EntryKind::from_u8(data[0]).ok_or_else(invalid)?;
+            let len = u16::from_be_bytes([data[1], data[2]]) as usize;
+            let value = data.get(3..3 + len).ok_or_else(invalid)?;
+            let value = String::from_utf8(value.to_vec()).map_err(|_| invalid())?;
+
+            entries.push(kind, value);
+            data = &data[3 + len..];
+        }
+
+        Ok(entries)
+    }
+}
+
+/// Encode entries into binary format.
+pub fn encode(entries: &RuleSetEntries) -> Result<Vec<u8>, RouteError> {
+    let mut buf = MAGIC.to_vec();
+
+    for (kind, value) in entries.iter() {
+        let len = u16::try_from(value.len())
+            .map_err(|_| RouteError::RuleSet(format!("entry too long ({})", value)))?;
+
+        buf.push(kind as u8);
+        buf.extend_from_slice(&len.to_be_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    Ok(buf)
+}
+
+/// Compiled matcher of a rule set.
+#[derive(Debug, Default)]
+pub struct RuleSetMatcher {
+    pub domain: DomainMatcher,
+    pub ip: IpMatcher,
+}
+
+impl RuleSetMatcher {
+    pub fn new(entries: &RuleSetEntries) -> Result<Self, RouteError> {
+        Ok(Self {
+            domain: DomainMatcher::new(
+                &entries.domain,
+                &entries.domain_suffix,
+                &entries.domain_keyword,
+                &entries.domain_regex,
+            )?,
+            ip: IpMatcher::new(&entries.ip_cidr)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct RuleSet {
+    tag: String,
+    path: PathBuf,
+    format: RuleSetFormat,
+    interval: Duration,
+
+    matcher: RwLock<Arc<RuleSetMatcher>>,
+    // modified time and length of the loaded file
+    loaded: Mutex<Option<(SystemTime, u64)>>,
+}
+
+impl RuleSet {
+    pub fn init(opt: RuleSetOption) -> Result<Self, RouteError> {
+        let rule_set = Self {
+            tag: opt.tag,
+            path: PathBuf::from(opt.path),
+            format: opt.format,
+            interval: opt.interval,
+            matcher: RwLock::new(Arc::default()),
+            loaded: Mutex::new(None),
+        };
+
+        rule_set.reload()?;
+
+        Ok(rule_set)
+    }
+
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn matcher(&self) -> Arc<RuleSetMatcher> {
+        self.matcher.read().unwrap().clone()
+    }
+
+    pub fn is_match_domain(&self, domain: &str) -> bool {
+        self.matcher().domain.is_match(domain)
+    }
+
+    pub fn is_match_ip(&self, ip: &IpAddr) -> bool {
+        self.matcher().ip.is_match(ip)
+    }
+
+    /// Whether the loaded file has ip cidr entries.
+    pub fn has_ip(&self) -> bool {
+        !self.matcher().ip.is_empty()
+    }
+
+    /// Read the file again if it changed since last load, return true if reloaded.
+    /// On error the previous matcher is kept.
+    pub fn reload(&self) -> Result<bool, RouteError> {
+        let err =
+            |e: std::io::Error| RouteError::RuleSet(format!("{} ({})", self.path.display(), e));
+
+        let meta = fs::metadata(&self.path).map_err(err)?;
+        let stamp = (meta.modified().map_err(err)?, meta.len());
+
+        if *self.loaded.lock().unwrap() == Some(stamp) {
+            return Ok(false);
+        }
+
+        let entries = match self.format {
+            RuleSetFormat::Text => {
+                RuleSetEntries::parse_text(&fs::read_to_string(&self.path).map_err(err)?)?
+            }
+            RuleSetFormat::Binary => {
+                RuleSetEntries::parse_binary(&fs::read(&self.path).map_err(err)?)?
+            }
+        };
+        let matcher = RuleSetMatcher::new(&entries)?;
+
+        *self.matcher.write().unwrap() = Arc::new(matcher);
+        *self.loaded.lock().unwrap() = Some(stamp);
+
+        Ok(true)
+    }
+
+    /// Check the file for changes every interval, never return.
+    pub async fn watch(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(self.interval).await;
+
+            match self.reload() {
+                Ok(true) => tracing::info!("[route] rule set [{}] reloaded", self.tag),
+                Ok(false) => {}
+                Err(e) => tracing::error!("[route] rule set [{}] {}", self.tag, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "
+        # comment
+        domain:full.com
+        domain_suffix:example.com # inline comment
+        domain_keyword:tracker
+        domain_regex:^ads?\\d*\\.
+        ip_cidr:10.0.0.0/8
+        suffix.org
+        192.168.0.1
+        fd00::/8
+    ";
+
+    #[test]
+    fn test_parse_text() {
+        let entries = RuleSetEntries::parse_text(TEXT).unwrap();
+
+        assert_eq!(entries.domain, vec!["full.com"]);
+        assert_eq!(entries.domain_suffix, vec!["example.com", "suffix.org"]);
+        assert_eq!(entries.domain_keyword, vec!["tracker"]);
+        assert_eq!(entries.domain_regex, vec!["^ads?\\d*\\."]);
+        assert_eq!(
+            entries.ip_cidr,
+            vec!["10.0.0.0/8", "192.168.0.1", "fd00::/8"]
+        );
+
+        assert!(RuleSetEntries::parse_text("unknown:value").is_err());
+    }
+
+    #[test]
+    fn test_binary_roundtrip() {
+        let entries = RuleSetEntries::parse_text(TEXT).unwrap();
+        let data = encode(&entries).unwrap();
+        let decoded = RuleSetEntries::parse_binary(&data).unwrap();
+
+        assert_eq!(
+            entries.iter().collect::<Vec<_>>(),
+            decoded.iter().collect::<Vec<_>>()
+        );
+        assert!(RuleSetEntries::parse_binary(&data[..data.len() - 1]).is_err());
+        assert!(RuleSetEntries::parse_binary(b"XXXX").is_err());
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("kapibara-rule-set-{}", std::process::id()));
+        fs::write(&path, "example.com\n").unwrap();
+
+        let rule_set = RuleSet::init(RuleSetOption {
+            tag: "test".into(),
+            path: path.to_string_lossy().into(),
+            format: RuleSetFormat::Text,
+            interval: default_interval(),
+        })
+        .unwrap();
+
+        assert!(rule_set.is_match_domain("www.example.com"));
+        assert!(!rule_set.is_match_ip(&"10.0.0.1".parse().unwrap()));
+        assert!(!rule_set.has_ip());
+        assert!(!rule_set.reload().unwrap());
+
+        fs::write(&path, "example.org\n10.0.0.0/8\n").unwrap();
+        assert!(rule_set.reload().unwrap());
+
+        assert!(!rule_set.is_match_domain("www.example.com"));
+        assert!(rule_set.is_match_domain("www.example.org"));
+        assert!(rule_set.is_match_ip(&"10.0.0.1".parse().unwrap()));
+        assert!(rule_set.has_ip());
+
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,341 @@
+//! Kapibara Sniff
+//!
+//! Recover the domain from the first bytes sent by client,
+//! TLS ClientHello SNI or HTTP Host header.
+
+use std::{net::IpAddr, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::io::Rewind;
+
+const SNIFF_BUF_SIZE: usize = 4 * 1024;
+
+fn default_timeout() -> Duration {
+    Duration::from_millis(300)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SniffOption {
+    // wait for the first client bytes, default 300ms
+    #[serde(default = "default_timeout")]
+    pub timeout: Duration,
+    // replace ip destination with sniffed domain,
+    // default only use it for routing
+    #[serde(default)]
+    pub override_dest: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SniffResult {
+    Found(String),
+    // looks like the protocol, but need more bytes
+    Incomplete,
+    NotFound,
+}
+
+/// Read from stream until domain found or timeout,
+/// the bytes read are replayed by the returned stream.
+pub async fn sniff<S>(mut stream: Rewind<S>, timeout: Duration) -> (Rewind<S>, Option<String>)
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(SNIFF_BUF_SIZE);
+
+    let result = tokio::time::timeout(timeout, async {
+        let mut chunk = [0u8; SNIFF_BUF_SIZE];
+        while buf.len() < SNIFF_BUF_SIZE {
+            let n = match stream.read(&mut chunk[..SNIFF_BUF_SIZE - buf.len()]).await {
+                Ok(0) | Err(_) => return None,
+                Ok(n) => n,
+            };
+            buf.extend_from_slice(&chunk[..n]);
+
+            match sniff_buf(&buf) {
+                SniffResult::Found(domain) => return Some(domain),
+                SniffResult::Incomplete => continue,
+                SniffResult::NotFound => return None,
+            }
+        }
+
+        None
+    })
+    .await
+    .unwrap_or(None);
+
+    stream.rewind(buf);
+
+    (stream, result)
+}
+
+pub fn sniff_buf(buf: &[u8]) -> SniffResult {
+    match sniff_tls(buf) {
+        SniffResult::NotFound => sniff_http(buf),
+        other => other,
+    }
+}
+
+pub fn sniff_tls(buf: &[u8]) -> SniffResult {
+    // record header: type(1) version(2) length(2)
+    if buf.is_empty() {
+        return SniffResult::Incomplete;
+    }
+    if buf[0] != 0x16 {
+        return SniffResult::NotFound;
+    }
+    if buf.len() < 5 {
+        return SniffResult::Incomplete;
+    }
+    if buf[1] != 0x03 {
+        return SniffResult::NotFound;
+    }
+
+    let record_len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    let Some(record) = buf.get(5..5 + record_len) else {
+        return SniffResult::Incomplete;
+    };
+
+    match parse_client_hello(record) {
+        Some(Some(domain)) => SniffResult::Found(domain),
+        _ => SniffResult::NotFound,
+    }
+}
+
+// return none if malformed, some(none) if no sni
+fn parse_client_hello(data: &[u8]) -> Option<Option<String>> {
+    let mut r = Reader(data);
+
+    // handshake type: client hello
+    if r.u8()? != 0x01 {
+        return None;
+    }
+    let len = r.u24()?;
+    let mut r = Reader(r.take(len)?);
+
+    // client version(2) random(32)
+    r.take(34)?;
+    // session id
+    let n = r.u8()? as usize;
+    r.take(n)?;
+    // cipher suites
+    let n = r.u16()? as usize;
+    r.take(n)?;
+    // compression methods
+    let n = r.u8()? as usize;
+    r.take(n)?;
+
+    if r.0.is_empty() {
+        return Some(None);
+    }
+
+    let n = r.u16()? as usize;
+    let mut exts = Reader(r.take(n)?);
+
+    while !exts.0.is_empty() {
+        let typ = exts.u16()?;
+        let n = exts.u16()? as usize;
+        let data = exts.take(n)?;
+
+        // server name
+        if typ == 0x0000 {
+            let mut list = Reader(data);
+            let n = list.u16()? as usize;
+            let mut list = Reader(list.take(n)?);
+
+            while !list.0.is_empty() {
+                let name_type = list.u8()?;
+                let n = list.u16()? as usize;
+                let name = list.take(n)?;
+
+                // host name
+                if name_type == 0 {
+                    let name = std::str::from_utf8(name).ok()?;
+                    return Some(to_domain(name));
+                }
+            }
+        }
+    }
+
+    Some(None)
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let b = self.take(3)?;
+        Some(((b[0] as usize) << 16) | ((b[1] as usize) << 8) | b[2] as usize)
+    }
+}
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"HEAD ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"TRACE ",
+    b"CONNECT ",
+];
+
+pub fn sniff_http(buf: &[u8]) -> SniffResult {
+    let is_http = HTTP_METHODS.iter().any(|m| {
+        let n = m.len().min(buf.len());
+        buf[..n] == m[..n]
+    });
+    if !is_http {
+        return SniffResult::NotFound;
+    }
+
+    let text = String::from_utf8_lossy(buf);
+    let Some((head, _)) = text.split_once("\r\n\r\n") else {
+        return SniffResult::Incomplete;
+    };
+
+    for line in head.split("\r\n").skip(1) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("host") {
+            return match to_domain(strip_port(value.trim())) {
+                Some(domain) => SniffResult::Found(domain),
+                None => SniffResult::NotFound,
+            };
+        }
+    }
+
+    SniffResult::NotFound
+}
+
+fn strip_port(host: &str) -> &str {
+    // [::1]:80
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+
+    match host.rsplit_once(':') {
+        Some((h, port)) if !h.contains(':') && port.parse::<u16>().is_ok() => h,
+        _ => host,
+    }
+}
+
+// ip address is not a domain
+fn to_domain(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.');
+    if host.is_empty() || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+
+    Some(host.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    fn client_hello(sni: &str) -> Vec<u8> {
+        let name = sni.as_bytes();
+
+        let mut sni_ext = vec![];
+        sni_ext.extend_from_slice(&((name.len() + 3) as u16).to_be_bytes());
+        sni_ext.push(0);
+        sni_ext.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        sni_ext.extend_from_slice(name);
+
+        let mut exts = vec![];
+        // supported versions, ignored
+        exts.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        exts.extend_from_slice(&[0x00, 0x00]);
+        exts.extend_from_slice(&(sni_ext.len() as u16).to_be_bytes());
+        exts.extend_from_slice(&sni_ext);
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.push(0);
+        hello.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+        hello.extend_from_slice(&[0x01, 0x00]);
+        hello.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        hello.extend_from_slice(&exts);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_sniff_tls() {
+        let hello = client_hello("www.Example.com");
+
+        assert_eq!(
+            sniff_buf(&hello),
+            SniffResult::Found("www.example.com".into())
+        );
+        assert_eq!(sniff_buf(&hello[..20]), SniffResult::Incomplete);
+        assert_eq!(sniff_buf(&client_hello("1.1.1.1")), SniffResult::NotFound);
+    }
+
+    #[test]
+    fn test_sniff_http() {
+        let req = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nHost: example.com:8080\r\n\r\n";
+
+        assert_eq!(sniff_buf(req), SniffResult::Found("example.com".into()));
+        assert_eq!(sniff_buf(&req[..20]), SniffResult::Incomplete);
+        assert_eq!(sniff_buf(b"GE"), SniffResult::Incomplete);
+        assert_eq!(
+            sniff_buf(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+            SniffResult::NotFound
+        );
+        assert_eq!(sniff_buf(b"SSH-2.0-OpenSSH\r\n"), SniffResult::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_replay() {
+        let (mut tx, rx) = duplex(SNIFF_BUF_SIZE);
+        let hello = client_hello("example.com");
+        tx.write_all(&hello).await.unwrap();
+
+        let (mut stream, domain) = sniff(Rewind::new(rx), default_timeout()).await;
+        assert_eq!(domain, Some("example.com".into()));
+
+        drop(tx);
+        let mut replay = vec![];
+        stream.read_to_end(&mut replay).await.unwrap();
+        assert_eq!(replay, hello);
+    }
+
+    #[tokio::test]
+    async fn test_sniff_timeout() {
+        let (_tx, rx) = duplex(SNIFF_BUF_SIZE);
+
+        let (_, domain) = sniff(Rewind::new(rx), Duration::from_millis(10)).await;
+        assert_eq!(domain, None);
+    }
+}
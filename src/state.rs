@@ -0,0 +1,71 @@
+//! Kapibara State
+//!
+//! Runtime state that survives restart, saved as json.
+
+use std::{collections::HashMap, fs, path::PathBuf, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::OutboundError;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct StateFile {
+    // group tag -> selected member
+    #[serde(default)]
+    selected: HashMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    path: Option<PathBuf>,
+    file: Mutex<StateFile>,
+}
+
+impl State {
+    /// Missing or broken file starts with empty state.
+    pub fn load(path: Option<String>) -> Self {
+        let path = path.map(PathBuf::from);
+
+        let file = match path {
+            Some(ref p) => match fs::read_to_string(p) {
+                Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                    tracing::warn!("[state] invalid {} ({})", p.display(), e);
+                    StateFile::default()
+                }),
+                Err(_) => StateFile::default(),
+            },
+            None => StateFile::default(),
+        };
+
+        Self {
+            path,
+            file: Mutex::new(file),
+        }
+    }
+
+    pub fn selected(&self, group: &str) -> Option<String> {
+        self.file.lock().unwrap().selected.get(group).cloned()
+    }
+
+    pub fn set_selected(&self, group: &str, member: &str) -> Result<(), OutboundError> {
+        let mut file = self.file.lock().unwrap();
+        file.selected.insert(group.to_owned(), member.to_owned());
+
+        self.save(&file)
+    }
+
+    fn save(&self, file: &StateFile) -> Result<(), OutboundError> {
+        let Some(ref path) = self.path else {
+            return Ok(());
+        };
+
+        let data =
+            serde_json::to_string_pretty(file).map_err(|e| OutboundError::State(e.to_string()))?;
+
+        // write to temp file first, so a crash never leaves half a file
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)
+            .and_then(|_| fs::rename(&tmp, path))
+            .map_err(|e| OutboundError::State(format!("{} ({})", path.display(), e)))
+    }
+}
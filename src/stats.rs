@@ -0,0 +1,209 @@
+//! Kapibara Stats
+//!
+//! Cumulative traffic and connection counters by inbound, outbound and user.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrafficSnapshot {
+    pub upload: u64,
+    pub download: u64,
+    pub connections: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatsSnapshot {
+    pub inbound: HashMap<String, TrafficSnapshot>,
+    pub outbound: HashMap<String, TrafficSnapshot>,
+    pub user: HashMap<String, TrafficSnapshot>,
+}
+
+impl TrafficSnapshot {
+    fn since(&self, base: &TrafficSnapshot) -> TrafficSnapshot {
+        TrafficSnapshot {
+            upload: self.upload.saturating_sub(base.upload),
+            download: self.download.saturating_sub(base.download),
+            connections: self.connections.saturating_sub(base.connections),
+        }
+    }
+}
+
+// counters only grow, reset moves the base subtracted from snapshot
+#[derive(Debug, Default)]
+struct Traffic {
+    upload: AtomicU64,
+    download: AtomicU64,
+    connections: AtomicU64,
+    base: Mutex<TrafficSnapshot>,
+}
+
+impl Traffic {
+    fn total(&self) -> TrafficSnapshot {
+        TrafficSnapshot {
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+            connections: self.connections.load(Ordering::Relaxed),
+        }
+    }
+
+    fn snapshot(&self) -> TrafficSnapshot {
+        self.total().since(&self.base.lock().unwrap())
+    }
+
+    fn reset(&self) -> TrafficSnapshot {
+        let mut base = self.base.lock().unwrap();
+        let total = self.total();
+        let snapshot = total.since(&base);
+        *base = total;
+        snapshot
+    }
+}
+
+#[derive(Debug, Default)]
+struct Table(RwLock<HashMap<String, Arc<Traffic>>>);
+
+impl Table {
+    fn get(&self, key: &str) -> Arc<Traffic> {
+        if let Some(t) = self.0.read().unwrap().get(key) {
+            return t.clone();
+        }
+
+        self.0
+            .write()
+            .unwrap()
+            .entry(key.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    fn collect<F>(&self, f: F) -> HashMap<String, TrafficSnapshot>
+    where
+        F: Fn(&Traffic) -> TrafficSnapshot,
+    {
+        self.0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(k, t)| (k.to_owned(), f(t)))
+            .collect()
+    }
+}
+
+/// Keys a connection is counted under, outbound has
+/// both group and the member actually connected.
+#[derive(Debug, Clone, Copy)]
+pub struct StatsKey<'a> {
+    pub inbound: &'a str,
+    pub outbound: &'a [&'a str],
+    pub user: Option<&'a str>,
+}
+
+#[derive(Debug, Default)]
+pub struct Stats {
+    inbound: Table,
+    outbound: Table,
+    user: Table,
+}
+
+impl Stats {
+    fn tables<'a>(&'a self, key: &StatsKey<'a>) -> Vec<Arc<Traffic>> {
+        let mut tables = vec![self.inbound.get(key.inbound)];
+        tables.extend(key.outbound.iter().map(|o| self.outbound.get(o)));
+        if let Some(user) = key.user {
+            tables.push(self.user.get(user));
+        }
+        tables
+    }
+
+    /// Count a closed connection and its traffic.
+    pub fn record(&self, key: &StatsKey, upload: u64, download: u64) {
+        for t in self.tables(key) {
+            t.upload.fetch_add(upload, Ordering::Relaxed);
+            t.download.fetch_add(download, Ordering::Relaxed);
+            t.connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Counters since the last reset.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            inbound: self.inbound.collect(Traffic::snapshot),
+            outbound: self.outbound.collect(Traffic::snapshot),
+            user: self.user.collect(Traffic::snapshot),
+        }
+    }
+
+    /// Counters since start, not changed by reset, for metrics.
+    pub fn total(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            inbound: self.inbound.collect(Traffic::total),
+            outbound: self.outbound.collect(Traffic::total),
+            user: self.user.collect(Traffic::total),
+        }
+    }
+
+    /// Return counters and set them to zero, totals are kept.
+    pub fn reset(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            inbound: self.inbound.collect(Traffic::reset),
+            outbound: self.outbound.collect(Traffic::reset),
+            user: self.user.collect(Traffic::reset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats::default();
+
+        let key = StatsKey {
+            inbound: "in",
+            outbound: &["proxy", "out-1"],
+            user: Some("alice"),
+        };
+        stats.record(&key, 10, 100);
+
+        let key = StatsKey {
+            inbound: "in",
+            outbound: &["direct"],
+            user: None,
+        };
+        stats.record(&key, 1, 2);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(
+            snapshot.inbound["in"],
+            TrafficSnapshot {
+                upload: 11,
+                download: 102,
+                connections: 2
+            }
+        );
+        assert_eq!(snapshot.outbound["proxy"], snapshot.outbound["out-1"]);
+        assert_eq!(snapshot.outbound["direct"].upload, 1);
+        assert_eq!(snapshot.user.len(), 1);
+        assert_eq!(snapshot.user["alice"].download, 100);
+
+        assert_eq!(stats.reset(), snapshot);
+        assert_eq!(stats.snapshot().inbound["in"], TrafficSnapshot::default());
+        assert_eq!(stats.total(), snapshot);
+
+        // counted from the last reset, total keeps growing
+        stats.record(&key, 1, 2);
+        assert_eq!(stats.snapshot().inbound["in"].upload, 1);
+        assert_eq!(stats.reset().inbound["in"].download, 2);
+        assert_eq!(stats.total().inbound["in"].upload, 12);
+    }
+}
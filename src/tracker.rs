@@ -0,0 +1,261 @@
+//! Kapibara Tracker
+//!
+//! Registry of active connections, with live byte counters and kill.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+/// Snapshot of an active connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnInfo {
+    pub id: u64,
+    pub inbound: String,
+    // outbound decided by route
+    pub outbound: String,
+    // outbound actually connected, differs from above for group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dialed: Option<String>,
+    // matched rule
+    pub rule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<SocketAddr>,
+    pub network: String,
+    pub dest: String,
+    pub start: SystemTime,
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// Connection fields known when it is tracked.
+#[derive(Debug, Clone)]
+pub struct ConnMeta {
+    pub inbound: String,
+    pub outbound: String,
+    pub rule: String,
+    pub user: Option<String>,
+    pub source: Option<SocketAddr>,
+    pub network: String,
+    pub dest: String,
+}
+
+#[derive(Debug)]
+pub struct Conn {
+    id: u64,
+    meta: ConnMeta,
+    dialed: Mutex<Option<String>>,
+    start: SystemTime,
+
+    upload: Arc<AtomicU64>,
+    download: Arc<AtomicU64>,
+    kill: watch::Sender<bool>,
+}
+
+impl Conn {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn meta(&self) -> &ConnMeta {
+        &self.meta
+    }
+
+    /// Counters of bytes from and to client.
+    pub fn counter(&self) -> (Arc<AtomicU64>, Arc<AtomicU64>) {
+        (self.upload.clone(), self.download.clone())
+    }
+
+    pub fn set_dialed(&self, tag: &str) {
+        *self.dialed.lock().unwrap() = Some(tag.to_owned());
+    }
+
+    pub fn kill(&self) {
+        self.kill.send_replace(true);
+    }
+
+    /// Resolve once killed.
+    pub async fn killed(&self) {
+        let mut rx = self.kill.subscribe();
+        let _ = rx.wait_for(|k| *k).await;
+    }
+
+    pub fn info(&self) -> ConnInfo {
+        ConnInfo {
+            id: self.id,
+            inbound: self.meta.inbound.to_owned(),
+            outbound: self.meta.outbound.to_owned(),
+            dialed: self.dialed.lock().unwrap().clone(),
+            rule: self.meta.rule.to_owned(),
+            user: self.meta.user.clone(),
+            source: self.meta.source,
+            network: self.meta.network.to_owned(),
+            dest: self.meta.dest.to_owned(),
+            start: self.start,
+            upload: self.upload.load(Ordering::Relaxed),
+            download: self.download.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Tracker {
+    next_id: AtomicU64,
+    conns: RwLock<HashMap<u64, Arc<Conn>>>,
+}
+
+impl Tracker {
+    /// Register a connection, it is removed when the guard dropped.
+    pub fn track(self: &Arc<Self>, meta: ConnMeta) -> Tracked {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let conn = Arc::new(Conn {
+            id,
+            meta,
+            dialed: Mutex::new(None),
+            start: SystemTime::now(),
+            upload: Arc::default(),
+            download: Arc::default(),
+            kill: watch::channel(false).0,
+        });
+
+        self.conns.write().unwrap().insert(id, conn.clone());
+
+        Tracked {
+            tracker: self.clone(),
+            conn,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.conns.read().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, id: u64) -> Option<ConnInfo> {
+        self.conns.read().unwrap().get(&id).map(|c| c.info())
+    }
+
+    /// All active connections, oldest first.
+    pub fn list(&self) -> Vec<ConnInfo> {
+        let mut list: Vec<ConnInfo> = self
+            .conns
+            .read()
+            .unwrap()
+            .values()
+            .map(|c| c.info())
+            .collect();
+        list.sort_by_key(|c| c.id);
+        list
+    }
+
+    pub fn kill(&self, id: u64) -> bool {
+        match self.conns.read().unwrap().get(&id) {
+            Some(c) => {
+                c.kill();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Kill all connections of user, return the number killed.
+    pub fn kill_user(&self, user: &str) -> usize {
+        self.kill_by(|c| c.meta.user.as_deref() == Some(user))
+    }
+
+    pub fn kill_all(&self) -> usize {
+        self.kill_by(|_| true)
+    }
+
+    fn kill_by<F: Fn(&Conn) -> bool>(&self, f: F) -> usize {
+        let conns = self.conns.read().unwrap();
+        let mut n = 0;
+        for c in conns.values().filter(|c| f(c)) {
+            c.kill();
+            n += 1;
+        }
+        n
+    }
+}
+
+/// Guard of a tracked connection.
+pub struct Tracked {
+    tracker: Arc<Tracker>,
+    conn: Arc<Conn>,
+}
+
+impl std::ops::Deref for Tracked {
+    type Target = Conn;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.tracker.conns.write().unwrap().remove(&self.conn.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta(user: Option<&str>) -> ConnMeta {
+        ConnMeta {
+            inbound: "in".into(),
+            outbound: "out".into(),
+            rule: "final".into(),
+            user: user.map(|u| u.into()),
+            source: Some("127.0.0.1:1234".parse().unwrap()),
+            network: "tcp".into(),
+            dest: "example.com:443".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tracker() {
+        let tracker = Arc::new(Tracker::default());
+
+        let a = tracker.track(meta(Some("alice")));
+        let b = tracker.track(meta(Some("bob")));
+        let c = tracker.track(meta(Some("alice")));
+        assert_eq!(tracker.len(), 3);
+
+        let (up, _) = a.counter();
+        up.fetch_add(10, Ordering::Relaxed);
+        a.set_dialed("out-1");
+        let info = tracker.get(a.id()).unwrap();
+        assert_eq!(info.upload, 10);
+        assert_eq!(info.dialed.as_deref(), Some("out-1"));
+
+        assert_eq!(tracker.kill_user("alice"), 2);
+        a.killed().await;
+        c.killed().await;
+        assert!(tracker.kill(b.id()));
+        b.killed().await;
+
+        let ids: Vec<u64> = tracker.list().iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![a.id(), b.id(), c.id()]);
+
+        drop(a);
+        drop(b);
+        drop(c);
+        assert!(tracker.is_empty());
+        assert!(!tracker.kill(1));
+    }
+}